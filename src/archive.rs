@@ -1,23 +1,39 @@
 use bzip2::bufread::BzDecoder;
+use bzip2::write::BzEncoder;
+use bzip2;
 use flate2::bufread::GzDecoder;
-use tar;
-use tempfile;
-use xz2::bufread::{XzDecoder, XzEncoder};
+use flate2::write::GzEncoder;
+use flate2;
+use libc;
+use sevenz_rust::{self, SevenZArchiveEntry};
+use tar::{self, EntryType};
+use walkdir::Error as WalkError;
+use walkdir::WalkDir;
+use xz2::bufread::XzDecoder;
+use xz2::stream::{Action, Check, MtStreamBuilder, Status, Stream};
+use xz2::write::XzEncoder;
+use zip::result::ZipError;
+use zip::ZipArchive;
+use zstd;
 
+use std::cmp;
+use std::fmt;
+use std::ffi::OsStr;
 use std::fs::{self, File, OpenOptions};
-use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 use std::os::unix::ffi::OsStrExt;
-use std::path::Path;
+use std::os::unix::fs::{symlink, FileTypeExt, MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
 
 use config::Config;
+use fakeroot::{Attributes, DeviceKind, FakerootError, Ownership};
+use metadata::{self, MetadataError};
 use package::{BuildFile, PackageError};
 use util::{self, path_to_string, UtilError};
 
 #[derive(Debug, Fail)]
 pub enum ArchiveError {
-    #[fail(display = "could not create temporary file: {}", _0)]
-    TempFile(#[cause] io::Error),
-
     #[fail(display = "could not open '{}': {}", _0, _1)]
     OpenFile(String, #[cause] io::Error),
 
@@ -27,8 +43,8 @@ pub enum ArchiveError {
     #[fail(display = "could not create file '{}': {}", _0, _1)]
     CreateFile(String, #[cause] io::Error),
 
-    #[fail(display = "could not decompress file: {}", _0)]
-    Decompress(#[cause] io::Error),
+    #[fail(display = "could not decompress '{}': {}", _0, _1)]
+    Decompress(String, #[cause] io::Error),
 
     #[fail(display = "could not compress file: {}", _0)]
     Compress(#[cause] io::Error),
@@ -36,9 +52,27 @@ pub enum ArchiveError {
     #[fail(display = "could not extract contents of '{}': {}", _0, _1)]
     Extract(String, #[cause] io::Error),
 
+    #[fail(display = "could not extract zip archive '{}': {}", _0, _1)]
+    Zip(String, #[cause] ZipError),
+
+    #[fail(display = "could not extract 7z archive '{}': {}", _0, _1)]
+    SevenZip(String, #[cause] sevenz_rust::Error),
+
+    #[fail(display = "refusing to extract '{}' from '{}' as it would end up outside of the build directory", _1, _0)]
+    UnsafePath(String, String),
+
+    #[fail(display = "refusing to extract '{}' from '{}' as it is inside of the symlink '{}'", _1, _0, _2)]
+    InsideSymlink(String, String, String),
+
+    #[fail(display = "refusing to extract '{}' from '{}' as it links to '{}', which is outside of the build directory", _1, _0, _2)]
+    UnsafeLink(String, String, String),
+
     #[fail(display = "could not archive '{}': {}", _0, _1)]
     Archive(String, #[cause] io::Error),
 
+    #[fail(display = "found invalid directory entry while archiving: {}", _0)]
+    DirEntry(#[cause] WalkError),
+
     #[fail(display = "could not create directory '{}': {}", _0, _1)]
     CreateDir(String, #[cause] io::Error),
 
@@ -48,6 +82,12 @@ pub enum ArchiveError {
     #[fail(display = "could not remove intermediate file at '{}': {}", _0, _1)]
     RemoveFile(String, #[cause] io::Error),
 
+    #[fail(display = "{}", _0)]
+    Metadata(#[cause] MetadataError),
+
+    #[fail(display = "{}", _0)]
+    Fakeroot(#[cause] FakerootError),
+
     #[fail(display = "{}", _0)]
     Util(#[cause] UtilError),
 
@@ -55,42 +95,88 @@ pub enum ArchiveError {
     Package(#[cause] PackageError),
 }
 
-pub struct Archiver {}
+// formats that built packages may be compressed with
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    None,
+    Gzip,
+    Bzip2,
+    Xz,
+    Zstd,
+}
 
-// XXX: if we try to build in a container, maybe extract to separately writable dirs or something?
+impl Compression {
+    // the names accepted on the command line (and in build files)
+    pub const NAMES: &'static [&'static str] = &["none", "gzip", "bzip2", "xz", "zstd"];
 
-trait CompDecoder<R: BufRead>: Sized + Read {
-    fn create(reader: R) -> Self;
-}
+    pub fn extension(&self) -> &'static str {
+        match *self {
+            Compression::None => ".tar",
+            Compression::Gzip => ".tar.gz",
+            Compression::Bzip2 => ".tar.bz2",
+            Compression::Xz => ".tar.xz",
+            Compression::Zstd => ".tar.zst",
+        }
+    }
 
-impl<R: BufRead> CompDecoder<R> for GzDecoder<R> {
-    fn create(reader: R) -> Self {
-        Self::new(reader)
+    // wraps the given reader such that reading from the result yields the decompressed data
+    fn decoder<'a, R: BufRead + 'a>(&self, reader: R) -> io::Result<Box<dyn Read + 'a>> {
+        Ok(match *self {
+            Compression::None => Box::new(reader),
+            Compression::Gzip => Box::new(GzDecoder::new(reader)),
+            Compression::Bzip2 => Box::new(BzDecoder::new(reader)),
+            Compression::Xz => Box::new(XzDecoder::new(reader)),
+            Compression::Zstd => Box::new(zstd::Decoder::new(reader)?),
+        })
     }
 }
 
-impl<R: BufRead> CompDecoder<R> for BzDecoder<R> {
-    fn create(reader: R) -> Self {
-        Self::new(reader)
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "gzip" => Ok(Compression::Gzip),
+            "bzip2" => Ok(Compression::Bzip2),
+            "xz" => Ok(Compression::Xz),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(format!("unknown compression format '{}'", s)),
+        }
     }
 }
 
-impl<R: BufRead> CompDecoder<R> for XzDecoder<R> {
-    fn create(reader: R) -> Self {
-        Self::new(reader)
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Compression::None => "none",
+            Compression::Gzip => "gzip",
+            Compression::Bzip2 => "bzip2",
+            Compression::Xz => "xz",
+            Compression::Zstd => "zstd",
+        };
+        write!(f, "{}", name)
     }
 }
 
+pub struct Archiver {}
+
+// XXX: if we try to build in a container, maybe extract to separately writable dirs or something?
+
 impl Archiver {
     const XZ_LEVEL: u32 = 6;
+    // zstd's own default, which is already much faster than xz while still compressing well
+    const ZSTD_LEVEL: i32 = 3;
 
     pub fn new() -> Self {
         Self {}
     }
 
     pub fn package(&self, config: &Config, pkg: &BuildFile) -> Result<(), ArchiveError> {
+        // the intermediate file must not be named like an uncompressed package
         let tar_path = pkg.base_dir(config)
-            .join(format!("{}-{}.tar", pkg.name(), pkg.version()));
+            .join(format!("{}-{}.tar.tmp", pkg.name(), pkg.version()));
         let mut tar_file = OpenOptions::new()
             .read(true)
             .write(true)
@@ -99,16 +185,39 @@ impl Archiver {
             .open(&tar_path)
             .map_err(|e| ArchiveError::OpenFile(path_to_string(&tar_path), e))?;
 
+        // the archive is assembled by hand rather than with append_dir_all() so that the result
+        // only depends on the contents of pkgdir (and SOURCE_DATE_EPOCH), allowing anyone to
+        // reproduce it bit-for-bit
         {
             let mut builder = tar::Builder::new(BufWriter::new(&mut tar_file));
-            builder.follow_symlinks(false);
-            // XXX: set header mode?
             // XXX: do we care what type of tar file?  (default is GNU)
 
+            let ownership = Ownership::load(config, pkg).map_err(ArchiveError::Fakeroot)?;
+
+            // the metadata goes first so it can be read without going through the whole archive
+            let pkginfo = metadata::pkginfo(config, pkg).map_err(ArchiveError::Metadata)?;
+            let mtree = metadata::mtree(&pkg.pkg_dir(config), &ownership)
+                .map_err(ArchiveError::Metadata)?;
+            for (name, contents) in &[(metadata::PKGINFO, pkginfo), (metadata::MTREE, mtree)] {
+                let archive_err = |e| ArchiveError::Archive(name.to_string(), e);
+
+                let mut header = self.new_header(&Attributes::root()).map_err(&archive_err)?;
+                header.set_size(contents.len() as u64);
+                header.set_mode(0o644);
+                header.set_mtime(metadata::build_date(config));
+                builder
+                    .append_data(&mut header, name, contents.as_bytes())
+                    .map_err(&archive_err)?;
+            }
+
             let pkgdir = pkg.pkg_dir(config);
+            let walker = WalkDir::new(&pkgdir).sort_by(|a, b| a.file_name().cmp(b.file_name()));
+            for entry in walker {
+                let entry = entry.map_err(ArchiveError::DirEntry)?;
+                self.append_entry(config, &mut builder, &pkgdir, entry.path(), &ownership)?;
+            }
             builder
-                .append_dir_all(".", &pkgdir)
-                .and_then(|_| builder.finish())
+                .finish()
                 .map_err(|e| ArchiveError::Archive(path_to_string(&pkgdir), e))?;
         }
 
@@ -117,15 +226,15 @@ impl Archiver {
             .seek(SeekFrom::Start(0))
             .map_err(|e| ArchiveError::Seek(path_to_string(&tar_path), e))?;
 
-        let package_path =
-            pkg.base_dir(config)
-                .join(format!("{}-{}.tar.xz", pkg.name(), pkg.version()));
+        let package_path = pkg.package_path(config);
         let package_file = File::create(&package_path)
             .map_err(|e| ArchiveError::CreateFile(path_to_string(&package_path), e))?;
 
-        io::copy(
-            &mut XzEncoder::new(BufReader::new(tar_file), Self::XZ_LEVEL),
-            &mut BufWriter::new(package_file),
+        self.compress(
+            config,
+            pkg.compression(config),
+            BufReader::new(tar_file),
+            BufWriter::new(package_file),
         ).map_err(|e| ArchiveError::Compress(e))?;
 
         fs::remove_file(&tar_path)
@@ -134,6 +243,176 @@ impl Archiver {
         Ok(())
     }
 
+    // NOTE: all of the encoders always produce the same output for the same input
+    fn compress<R: Read, W: Write>(
+        &self,
+        config: &Config,
+        compression: Compression,
+        mut input: R,
+        mut output: W,
+    ) -> io::Result<()> {
+        match compression {
+            Compression::None => {
+                io::copy(&mut input, &mut output)?;
+            }
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(output, flate2::Compression::default());
+                io::copy(&mut input, &mut encoder)?;
+                output = encoder.finish()?;
+            }
+            Compression::Bzip2 => {
+                let mut encoder = BzEncoder::new(output, bzip2::Compression::Default);
+                io::copy(&mut input, &mut encoder)?;
+                output = encoder.finish()?;
+            }
+            Compression::Xz => {
+                // the multithreaded encoder splits the input into blocks whose size only depends
+                // on the preset, so the output is the same no matter how many threads are used
+                let stream = MtStreamBuilder::new()
+                    .preset(Self::XZ_LEVEL)
+                    .check(Check::Crc64)
+                    .threads(self.xz_threads(config))
+                    .encoder()?;
+                let mut encoder = XzEncoder::new_stream(output, stream);
+                io::copy(&mut input, &mut encoder)?;
+                output = encoder.finish()?;
+            }
+            Compression::Zstd => {
+                let mut encoder = zstd::Encoder::new(output, Self::ZSTD_LEVEL)?;
+                io::copy(&mut input, &mut encoder)?;
+                output = encoder.finish()?;
+            }
+        }
+        output.flush()
+    }
+
+    // packages may be compressed while other packages are being built, so only use our share of
+    // the CPUs
+    fn xz_threads(&self, config: &Config) -> u32 {
+        let builds = config.parallel_build.unwrap_or(1).max(1) as usize;
+        cmp::max(util::cpu_count() / builds, 1) as u32
+    }
+
+    // creates a header with the recorded ownership (as the users on the build host are
+    // irrelevant)
+    fn new_header(&self, attrs: &Attributes) -> io::Result<tar::Header> {
+        let mut header = tar::Header::new_gnu();
+        header.set_uid(attrs.owner.id);
+        header.set_gid(attrs.group.id);
+        header.set_username(&attrs.owner.name)?;
+        header.set_groupname(&attrs.group.name)?;
+        Ok(header)
+    }
+
+    fn append_entry<W: Write>(
+        &self,
+        config: &Config,
+        builder: &mut tar::Builder<W>,
+        pkgdir: &Path,
+        path: &Path,
+        ownership: &Ownership,
+    ) -> Result<(), ArchiveError> {
+        let archive_err = |e| ArchiveError::Archive(path_to_string(path), e);
+
+        let meta = fs::symlink_metadata(path).map_err(&archive_err)?;
+        let file_type = meta.file_type();
+
+        let rel = util::relative_to(pkgdir, path);
+        let name = if rel.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            rel
+        };
+
+        let attrs = ownership.attributes(rel, &meta);
+        let mut header = self.new_header(&attrs).map_err(&archive_err)?;
+        header.set_mode(meta.mode() & 0o7777);
+        // files created during the build should appear to be as old as the source code
+        let mtime = cmp::max(meta.mtime(), 0) as u64;
+        header.set_mtime(match config.source_date_epoch {
+            Some(epoch) => cmp::min(mtime, epoch),
+            None => mtime,
+        });
+
+        if let Some(device) = attrs.device {
+            // the install step only created a placeholder for the device
+            header.set_entry_type(match device.kind {
+                DeviceKind::Block => EntryType::block_special(),
+                DeviceKind::Char => EntryType::character_special(),
+                DeviceKind::Fifo => EntryType::fifo(),
+            });
+            if let Some(mode) = device.mode {
+                header.set_mode(mode & 0o7777);
+            }
+            header.set_device_major(device.major).map_err(&archive_err)?;
+            header.set_device_minor(device.minor).map_err(&archive_err)?;
+            header.set_size(0);
+            builder
+                .append_data(&mut header, name, io::empty())
+                .map_err(&archive_err)
+        } else if file_type.is_file() {
+            header.set_entry_type(EntryType::file());
+            header.set_size(meta.len());
+            let file = File::open(path).map_err(&archive_err)?;
+            builder
+                .append_data(&mut header, name, BufReader::new(file))
+                .map_err(&archive_err)
+        } else {
+            if file_type.is_dir() {
+                header.set_entry_type(EntryType::dir());
+            } else if file_type.is_symlink() {
+                header.set_entry_type(EntryType::symlink());
+                let target = fs::read_link(path).map_err(&archive_err)?;
+                header.set_link_name(target).map_err(&archive_err)?;
+            } else if file_type.is_fifo() {
+                header.set_entry_type(EntryType::fifo());
+            } else if file_type.is_char_device() || file_type.is_block_device() {
+                header.set_entry_type(if file_type.is_char_device() {
+                    EntryType::character_special()
+                } else {
+                    EntryType::block_special()
+                });
+                let (major, minor) = device_numbers(meta.rdev());
+                header.set_device_major(major).map_err(&archive_err)?;
+                header.set_device_minor(minor).map_err(&archive_err)?;
+            } else {
+                // sockets only make sense while something is listening on them
+                return Ok(());
+            }
+            header.set_size(0);
+            builder
+                .append_data(&mut header, name, io::empty())
+                .map_err(&archive_err)
+        }
+    }
+
+    // extracts the package built from the given build file into target_path (which is used to
+    // populate the sysroot of packages that depend on it)
+    pub fn install(
+        &self,
+        config: &Config,
+        pkg: &BuildFile,
+        target_path: &Path,
+    ) -> Result<(), ArchiveError> {
+        let package_path = pkg.package_path(config);
+        let file = File::open(&package_path)
+            .map_err(|e| ArchiveError::OpenFile(path_to_string(&package_path), e))?;
+        let decoder = pkg.compression(config)
+            .decoder(BufReader::new(file))
+            .map_err(|e| ArchiveError::Decompress(path_to_string(&package_path), e))?;
+
+        // unlike sources, we built the package ourselves, so it can be trusted
+        let mut archive = tar::Archive::new(decoder);
+        archive.set_preserve_permissions(true);
+        archive.set_unpack_xattrs(true);
+
+        // the metadata describes the package rather than being part of it (and would be
+        // overwritten by that of every other package installed to the same place)
+        self.unpack_entries(&mut archive, &package_path, target_path, |_, path| {
+            Ok(path != Path::new(metadata::PKGINFO) && path != Path::new(metadata::MTREE))
+        })
+    }
+
     // XXX: maybe should just create all necessary directories up-front (like a
     //      pkg.init_dirs(config) before calling download, build, etc.)
     pub fn extract(&self, config: &Config, pkg: &BuildFile) -> Result<(), ArchiveError> {
@@ -145,137 +424,1004 @@ impl Archiver {
         fs::create_dir(&target_path)
             .map_err(|e| ArchiveError::CreateDir(path_to_string(&target_path), e))?;
 
-        if pkg.skip_extract() {
-            return Ok(());
-        }
-
         for src in pkg.source() {
             let build_path = pkg.file_download_path(config, src)
                 .map_err(|e| ArchiveError::Package(e))?;
 
+            // patches are applied later on by the builder (straight from download_dir)
+            if pkg.is_patch(src) {
+                continue;
+            }
+
+            let target_path = match src.subdir() {
+                Some(subdir) => {
+                    let target_path = pkg.archive_out_dir(config).join(subdir);
+                    fs::create_dir_all(&target_path)
+                        .map_err(|e| ArchiveError::CreateDir(path_to_string(&target_path), e))?;
+                    target_path
+                }
+                None => pkg.archive_out_dir(config),
+            };
+
+            if src.noextract() {
+                util::copy_dir(&build_path, &target_path).map_err(ArchiveError::Util)?;
+                continue;
+            }
+
             if let Some(filename) = build_path.file_name() {
-                const IS_TAR: &[&[u8]] = &[
-                    b".tar.gz",
-                    b".tar.bz2",
-                    b".tar.xz",
-                    b".tgz",
-                    b".tbz",
-                    b".txz",
-                ];
-                const IS_GZ: &[&[u8]] = &[b".gz", b".tgz"];
-                const IS_BZIP2: &[&[u8]] = &[b".bz2", b".tbz"];
-                const IS_XZ: &[&[u8]] = &[b".xz", b".txz"];
-
-                let filename = filename.as_bytes();
-                let mut found = false;
-
-                let res = self.try_extraction(filename, &build_path, IS_GZ, |path| {
-                    self.decompress::<GzDecoder<_>>(path)
-                }).or_else(|| {
-                        self.try_extraction(filename, &build_path, IS_BZIP2, |path| {
-                            self.decompress::<BzDecoder<_>>(path)
-                        })
-                    })
-                    .or_else(|| {
-                        self.try_extraction(filename, &build_path, IS_XZ, |path| {
-                            self.decompress::<XzDecoder<_>>(path)
-                        })
-                    });
-
-                // should probably use .transpose() when that is stable
-                let file = match res {
-                    Some(Ok(file)) => {
-                        found = true;
-                        Some(file)
-                    }
-                    Some(Err(f)) => return Err(f),
-                    None => None,
+                // the name of the file is often meaningless (or just wrong), so the format is
+                // determined by looking at the contents instead
+                let contents = if build_path.is_dir() {
+                    None
+                } else {
+                    let file = File::open(&build_path)
+                        .map_err(|e| ArchiveError::OpenFile(path_to_string(&build_path), e))?;
+                    Some(
+                        sniff_contents(file)
+                            .map_err(|e| ArchiveError::Decompress(path_to_string(&build_path), e))?,
+                    )
                 };
 
-                if let Some(res) = self.try_extraction(filename, &build_path, IS_TAR, |path| {
-                    self.extract_tar(config, pkg, path, file)
-                }) {
-                    res?;
-                    found = true;
+                match contents {
+                    Some(Contents::Tar(reader)) => {
+                        self.unpack(reader, &build_path, &target_path)?;
+                    }
+                    Some(Contents::Zip) => {
+                        self.extract_zip(&build_path, &target_path)?;
+                    }
+                    Some(Contents::SevenZip) => {
+                        self.extract_7z(&build_path, &target_path)?;
+                    }
+                    Some(Contents::Compressed(reader, ext)) => {
+                        // just a single compressed file, so store it without the extension
+                        let filename = filename.as_bytes();
+                        let name = if filename.ends_with(ext.as_bytes()) {
+                            &filename[..filename.len() - ext.len()]
+                        } else {
+                            filename
+                        };
+                        let output_path = target_path.join(OsStr::from_bytes(name));
+                        self.decompress(reader, &build_path, &output_path)?;
+                    }
+                    Some(Contents::Other) | None => {
+                        // move the file/directory into place even though it wasn't extracted
+                        util::copy_dir(&build_path, &target_path)
+                            .map_err(ArchiveError::Util)?
+                    }
                 }
+            }
+        }
 
-                if !found {
-                    // move the file/directory into place even though it wasn't extracted
-                    util::copy_dir(&build_path, &pkg.archive_out_dir(config))
-                        .map_err(|e| ArchiveError::Util(e))?
+        Ok(())
+    }
+
+    // the archive is decompressed as it is being unpacked, so the uncompressed tarball never needs
+    // to be stored anywhere
+    fn unpack<R: Read>(
+        &self,
+        reader: R,
+        archive_path: &Path,
+        target_path: &Path,
+    ) -> Result<(), ArchiveError> {
+        let extract_err = |e| ArchiveError::Extract(path_to_string(archive_path), e);
+
+        let mut archive = tar::Archive::new(reader);
+        // XXX: do we care about permissions here?  most likely we only care when we are installing for real
+        archive.set_preserve_permissions(true);
+        // sources come from arbitrary upstreams, so they don't get to set extended attributes
+        archive.set_unpack_xattrs(false);
+
+        self.unpack_entries(&mut archive, archive_path, target_path, |entry, path| {
+            self.check_entry_path(archive_path, target_path, path)?;
+            if let Some(link) = entry.link_name().map_err(&extract_err)? {
+                let entry_type = entry.header().entry_type();
+                if entry_type.is_symlink() {
+                    self.check_symlink(archive_path, path, &link)?;
+                } else if entry_type.is_hard_link() {
+                    self.check_hard_link(archive_path, target_path, path, &link)?;
                 }
             }
+            Ok(true)
+        })
+    }
+
+    // unpacks the entries of the archive for which keep() returns true into target_path.  like
+    // Archive::unpack(), directories are created last so that their permissions don't prevent
+    // the creation of their contents
+    fn unpack_entries<R, F>(
+        &self,
+        archive: &mut tar::Archive<R>,
+        archive_path: &Path,
+        target_path: &Path,
+        mut keep: F,
+    ) -> Result<(), ArchiveError>
+    where
+        R: Read,
+        F: FnMut(&mut tar::Entry<R>, &Path) -> Result<bool, ArchiveError>,
+    {
+        let extract_err = |e| ArchiveError::Extract(path_to_string(archive_path), e);
+
+        let mut directories = vec![];
+        for entry in archive.entries().map_err(&extract_err)? {
+            let mut entry = entry.map_err(&extract_err)?;
+            let path = entry.path().map_err(&extract_err)?.into_owned();
+            if !keep(&mut entry, &path)? {
+                continue;
+            }
+
+            if entry.header().entry_type().is_dir() {
+                directories.push(entry);
+            } else {
+                entry.unpack_in(target_path).map_err(&extract_err)?;
+            }
+        }
+        for mut dir in directories {
+            dir.unpack_in(target_path).map_err(&extract_err)?;
         }
 
         Ok(())
     }
 
-    fn try_extraction<F: FnOnce(&Path) -> Result<File, ArchiveError>>(
+    // makes sure an entry with the given path ends up within target_path (which isn't the case if
+    // it uses "..", is absolute, or would be written through a symlink created by an earlier entry)
+    fn check_entry_path(
         &self,
-        filename: &[u8],
-        build_path: &Path,
-        exts: &[&[u8]],
-        action: F,
-    ) -> Option<Result<File, ArchiveError>> {
-        for ext in exts {
-            if filename.ends_with(ext) {
-                return Some(action(build_path));
+        archive_path: &Path,
+        target_path: &Path,
+        path: &Path,
+    ) -> Result<(), ArchiveError> {
+        let mut current = target_path.to_path_buf();
+        for component in path.components() {
+            match component {
+                Component::Normal(name) => {
+                    if current != target_path && is_symlink(&current) {
+                        Err(ArchiveError::InsideSymlink(
+                            path_to_string(archive_path),
+                            path_to_string(path),
+                            path_to_string(&current),
+                        ))?
+                    }
+                    current.push(name);
+                }
+                Component::CurDir => {}
+                _ => Err(ArchiveError::UnsafePath(
+                    path_to_string(archive_path),
+                    path_to_string(path),
+                ))?,
             }
         }
-        None
+        Ok(())
+    }
+
+    fn check_symlink(
+        &self,
+        archive_path: &Path,
+        path: &Path,
+        target: &Path,
+    ) -> Result<(), ArchiveError> {
+        if util::link_is_contained(path, target) {
+            Ok(())
+        } else {
+            Err(ArchiveError::UnsafeLink(
+                path_to_string(archive_path),
+                path_to_string(path),
+                path_to_string(target),
+            ))
+        }
     }
 
-    fn extract_tar(
+    // the target of a hard link is relative to the root of the archive, so it has to satisfy the
+    // same rules as the path of an entry (and must not be a symlink, as linking to it would create
+    // another symlink in a place where its target might refer to something else)
+    fn check_hard_link(
         &self,
-        config: &Config,
-        pkg: &BuildFile,
+        archive_path: &Path,
+        target_path: &Path,
+        path: &Path,
+        target: &Path,
+    ) -> Result<(), ArchiveError> {
+        self.check_entry_path(archive_path, target_path, target)?;
+        if is_symlink(&target_path.join(target)) {
+            Err(ArchiveError::UnsafeLink(
+                path_to_string(archive_path),
+                path_to_string(path),
+                path_to_string(target),
+            ))?
+        }
+        Ok(())
+    }
+
+    fn extract_zip(&self, archive_path: &Path, target_path: &Path) -> Result<(), ArchiveError> {
+        let zip_err = |e| ArchiveError::Zip(path_to_string(archive_path), e);
+
+        let file = File::open(archive_path)
+            .map_err(|e| ArchiveError::OpenFile(path_to_string(archive_path), e))?;
+        let mut archive = ZipArchive::new(BufReader::new(file)).map_err(&zip_err)?;
+        for i in 0..archive.len() {
+            let mut entry = archive.by_index(i).map_err(&zip_err)?;
+            let name = match entry.enclosed_name() {
+                Some(name) => name.to_owned(),
+                None => Err(ArchiveError::UnsafePath(
+                    path_to_string(archive_path),
+                    entry.name().to_string(),
+                ))?,
+            };
+            let (is_dir, mode) = (entry.is_dir(), entry.unix_mode());
+            self.unpack_entry(archive_path, target_path, &name, is_dir, mode, &mut entry)?;
+        }
+        Ok(())
+    }
+
+    fn extract_7z(&self, archive_path: &Path, target_path: &Path) -> Result<(), ArchiveError> {
+        // the callback can only return the 7z crate's errors, so ours are smuggled out instead
+        let mut failure = None;
+        let res = {
+            let extract_fn = |entry: &SevenZArchiveEntry, reader: &mut dyn Read, _: &PathBuf| {
+                // archives created on unix store the mode in the upper half of the attributes
+                let attrs = entry.windows_attributes();
+                let mode = if attrs & 0x8000 != 0 {
+                    Some(attrs >> 16)
+                } else {
+                    None
+                };
+                let name = Path::new(entry.name());
+                match self.unpack_entry(
+                    archive_path,
+                    target_path,
+                    name,
+                    entry.is_directory(),
+                    mode,
+                    reader,
+                ) {
+                    Ok(()) => Ok(true),
+                    Err(f) => {
+                        failure = Some(f);
+                        Err(sevenz_rust::Error::other("extraction failed"))
+                    }
+                }
+            };
+            sevenz_rust::decompress_file_with_extract_fn(archive_path, target_path, extract_fn)
+        };
+        match failure {
+            Some(f) => Err(f),
+            None => res.map_err(|e| ArchiveError::SevenZip(path_to_string(archive_path), e)),
+        }
+    }
+
+    // unlike tar, the zip and 7z crates leave writing out the entries to us
+    fn unpack_entry(
+        &self,
+        archive_path: &Path,
+        target_path: &Path,
+        name: &Path,
+        is_dir: bool,
+        mode: Option<u32>,
+        reader: &mut dyn Read,
+    ) -> Result<(), ArchiveError> {
+        self.check_entry_path(archive_path, target_path, name)?;
+
+        let extract_err = |e| ArchiveError::Extract(path_to_string(archive_path), e);
+
+        let path = target_path.join(name);
+        if is_dir {
+            return fs::create_dir_all(&path).map_err(&extract_err);
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(&extract_err)?;
+        }
+
+        match mode {
+            Some(mode) if mode & libc::S_IFMT == libc::S_IFLNK => {
+                // the contents of a symlink entry is the path it points to
+                let mut target = vec![];
+                reader.read_to_end(&mut target).map_err(&extract_err)?;
+                let target = Path::new(OsStr::from_bytes(&target));
+                self.check_symlink(archive_path, name, target)?;
+                symlink(target, &path).map_err(&extract_err)
+            }
+            _ => {
+                let file = File::create(&path).map_err(&extract_err)?;
+                let mut writer = BufWriter::new(file);
+                io::copy(reader, &mut writer).map_err(&extract_err)?;
+                writer.flush().map_err(&extract_err)?;
+                if let Some(mode) = mode {
+                    fs::set_permissions(&path, fs::Permissions::from_mode(mode & 0o7777))
+                        .map_err(&extract_err)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn decompress<R: Read>(
+        &self,
+        mut reader: R,
         build_path: &Path,
-        file: Option<File>,
-    ) -> Result<File, ArchiveError> {
-        let mut file = match file {
-            Some(file) => file,
-            None => File::open(build_path)
-                .map_err(|e| ArchiveError::OpenFile(path_to_string(&build_path), e))?,
+        output_path: &Path,
+    ) -> Result<(), ArchiveError> {
+        let output = File::create(output_path)
+            .map_err(|e| ArchiveError::CreateFile(path_to_string(output_path), e))?;
+        let mut writer = BufWriter::new(output);
+
+        let decompress_err = |e| ArchiveError::Decompress(path_to_string(build_path), e);
+        io::copy(&mut reader, &mut writer).map_err(&decompress_err)?;
+        writer.flush().map_err(&decompress_err)
+    }
+}
+
+// what a source file turned out to contain
+enum Contents<'a> {
+    // zip and 7z archives have to be read from the file itself, as their indexes are at the end
+    Zip,
+    SevenZip,
+    // a (possibly compressed) tarball
+    Tar(Box<dyn Read + 'a>),
+    // some other compressed file, along with the extension such files usually have
+    Compressed(Box<dyn Read + 'a>, &'static str),
+    Other,
+}
+
+// magic numbers identifying the formats sources may be compressed with
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const BZIP2_MAGIC: &[u8] = b"BZh";
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const LZIP_MAGIC: &[u8] = b"LZIP";
+// .lzma files don't actually have a magic number, but nearly all of them start with the default
+// properties followed by a dictionary size that is a multiple of 64KiB
+const LZMA_MAGIC: &[u8] = &[0x5d, 0x00, 0x00];
+
+// bzip2 streams continue with either their first block or (if empty) the end of the stream
+const BZIP2_BLOCK_MAGIC: &[u8] = &[0x31, 0x41, 0x59, 0x26, 0x53, 0x59];
+const BZIP2_END_MAGIC: &[u8] = &[0x17, 0x72, 0x45, 0x38, 0x50, 0x90];
+
+// the longest header we look at (that of .lzma files)
+const COMPRESSION_HEADER_LEN: usize = 13;
+
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+const SEVENZ_MAGIC: &[u8] = &[b'7', b'z', 0xbc, 0xaf, 0x27, 0x1c];
+
+// tar headers contain "ustar" (followed by either a NUL or spaces) at this offset
+const TAR_MAGIC_OFFSET: usize = 257;
+const TAR_MAGIC: &[u8] = b"ustar";
+
+fn sniff_contents<'a, R: Read + 'a>(reader: R) -> io::Result<Contents<'a>> {
+    let (start, reader) = peek(reader, SEVENZ_MAGIC.len())?;
+    if start.starts_with(ZIP_MAGIC) {
+        return Ok(Contents::Zip);
+    } else if start.starts_with(SEVENZ_MAGIC) {
+        return Ok(Contents::SevenZip);
+    }
+
+    // as the header has already been checked, any error from the decoder means the file is
+    // corrupt rather than not compressed after all
+    let (decoder, ext) = sniff_compression(reader)?;
+    let (start, decoder) = peek(decoder, TAR_MAGIC_OFFSET + TAR_MAGIC.len())?;
+    Ok(if start.len() > TAR_MAGIC_OFFSET && start[TAR_MAGIC_OFFSET..] == *TAR_MAGIC {
+        Contents::Tar(Box::new(decoder))
+    } else if let Some(ext) = ext {
+        Contents::Compressed(Box::new(decoder), ext)
+    } else {
+        Contents::Other
+    })
+}
+
+// determines how the stream is compressed from its header, returning the decompressed stream
+// along with the extension usually given to such files.  the magic numbers of some formats are
+// short enough to show up in other files (e.g. text starting with "BZh"), so the rest of the
+// header has to make sense as well
+fn sniff_compression<'a, R: Read + 'a>(
+    reader: R,
+) -> io::Result<(Box<dyn Read + 'a>, Option<&'static str>)> {
+    let (start, reader) = peek(reader, COMPRESSION_HEADER_LEN)?;
+    let reader = BufReader::new(reader);
+
+    let (decoder, ext): (Box<dyn Read + 'a>, _) = if is_gzip(&start) {
+        (Compression::Gzip.decoder(reader)?, ".gz")
+    } else if is_bzip2(&start) {
+        (Compression::Bzip2.decoder(reader)?, ".bz2")
+    } else if is_xz(&start) {
+        (Compression::Xz.decoder(reader)?, ".xz")
+    } else if is_zstd(&start) {
+        (Compression::Zstd.decoder(reader)?, ".zst")
+    } else if is_lzip(&start) {
+        (Box::new(LzipDecoder::new(reader)?), ".lz")
+    } else if is_lzma(&start) {
+        let stream = Stream::new_lzma_decoder(u64::MAX)?;
+        (Box::new(XzDecoder::new_stream(reader, stream)), ".lzma")
+    } else {
+        return Ok((Box::new(reader), None));
+    };
+    Ok((decoder, Some(ext)))
+}
+
+fn is_gzip(start: &[u8]) -> bool {
+    // the compression method has to be deflate and the reserved flags must not be set
+    start.len() >= 4 && start.starts_with(GZIP_MAGIC) && start[2] == 8 && start[3] & 0xe0 == 0
+}
+
+fn is_bzip2(start: &[u8]) -> bool {
+    // the block size is given as a digit from 1 to 9
+    start.len() >= 10
+        && start.starts_with(BZIP2_MAGIC)
+        && (b'1'..=b'9').contains(&start[3])
+        && (&start[4..10] == BZIP2_BLOCK_MAGIC || &start[4..10] == BZIP2_END_MAGIC)
+}
+
+fn is_xz(start: &[u8]) -> bool {
+    // only the lowest bits of the stream flags are in use, and they come with a CRC32
+    if start.len() < 12 || !start.starts_with(XZ_MAGIC) || start[6] != 0 || start[7] & 0xf0 != 0 {
+        return false;
+    }
+    let mut crc = flate2::Crc::new();
+    crc.update(&start[6..8]);
+    crc.sum() == le_u32(&start[8..12])
+}
+
+fn is_zstd(start: &[u8]) -> bool {
+    // the reserved bit of the frame header descriptor must not be set
+    start.len() >= 5 && start.starts_with(ZSTD_MAGIC) && start[4] & 0x08 == 0
+}
+
+fn is_lzip(start: &[u8]) -> bool {
+    start.len() >= 6
+        && start.starts_with(LZIP_MAGIC)
+        && start[4] == 1
+        && lzip_dict_size(start[5]).is_some()
+}
+
+fn is_lzma(start: &[u8]) -> bool {
+    // the uncompressed size is either unknown or (like liblzma demands) below 256GiB
+    start.len() >= COMPRESSION_HEADER_LEN
+        && start.starts_with(LZMA_MAGIC)
+        && (start[5..13].iter().all(|&b| b == 0xff) || (start[9] < 0x40 && start[10..13] == [0; 3]))
+}
+
+fn le_u32(bytes: &[u8]) -> u32 {
+    bytes.iter().rev().fold(0, |num, &b| (num << 8) | u32::from(b))
+}
+
+fn le_u64(bytes: &[u8]) -> u64 {
+    bytes.iter().rev().fold(0, |num, &b| (num << 8) | u64::from(b))
+}
+
+// a stream with the bytes read from its start put back in front of it
+type Peeked<R> = io::Chain<io::Cursor<Vec<u8>>, R>;
+
+// reads up to len bytes from the start of the stream, returning them along with a stream that
+// still yields everything (including the bytes that were read)
+fn peek<R: Read>(mut reader: R, len: usize) -> io::Result<(Vec<u8>, Peeked<R>)> {
+    let mut start = Vec::with_capacity(len);
+    reader.by_ref().take(len as u64).read_to_end(&mut start)?;
+    Ok((start.clone(), io::Cursor::new(start).chain(reader)))
+}
+
+// the dictionary size of an lzip member is a power of two minus some number of sixteenths of it
+fn lzip_dict_size(flags: u8) -> Option<u32> {
+    let bits = flags & 0x1f;
+    if !(12..=29).contains(&bits) {
+        return None;
+    }
+    let base = 1u32 << bits;
+    Some(base - (base / 16) * u32::from(flags >> 5))
+}
+
+// liblzma (at least the version we use) doesn't understand lzip files, but each of their members
+// is just a small header and trailer around raw LZMA data, so we give it an equivalent .lzma
+// header instead and check the trailer ourselves
+struct LzipDecoder<R: Read> {
+    obj: BufReader<R>,
+    // the .lzma header for the current member, which is fed to liblzma before the member's data
+    header: io::Cursor<Vec<u8>>,
+    stream: Stream,
+    crc: flate2::Crc,
+    member_done: bool,
+    done: bool,
+}
+
+impl<R: Read> LzipDecoder<R> {
+    fn new(reader: R) -> io::Result<Self> {
+        let mut obj = BufReader::new(reader);
+        let header = Self::read_header(&mut obj)?;
+
+        Ok(Self {
+            obj,
+            header: io::Cursor::new(header),
+            stream: Stream::new_lzma_decoder(u64::MAX)?,
+            crc: flate2::Crc::new(),
+            member_done: false,
+            done: false,
+        })
+    }
+
+    // reads the header of a member and returns the .lzma header to use in its place
+    fn read_header(reader: &mut BufReader<R>) -> io::Result<Vec<u8>> {
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+        let mut header = [0; 6];
+        read_lzip(reader, &mut header)?;
+        if &header[..4] != LZIP_MAGIC {
+            return Err(invalid("trailing data after the last lzip member"));
+        } else if header[4] != 1 {
+            return Err(invalid("unsupported lzip version"));
+        }
+        let dict_size = match lzip_dict_size(header[5]) {
+            Some(size) => size,
+            None => return Err(invalid("invalid lzip dictionary size")),
         };
-        {
-            let reader = BufReader::new(&mut file);
 
-            let mut archive = tar::Archive::new(reader);
-            // FIXME: extract to correct path (it might make sense to extract to a directory based on the name of the file
-            //        in case two files that were downloaded conflict, but this seems unlikely to occur)
-            let target_path = pkg.archive_out_dir(config);
+        // properties for lc=3, lp=0, pb=2 (which lzip always uses), the dictionary size, and an
+        // unknown uncompressed size (as the data ends with an end-of-stream marker)
+        let mut lzma_header = vec![0x5d];
+        lzma_header.extend((0..4).map(|i| (dict_size >> (8 * i)) as u8));
+        lzma_header.extend(&[0xff; 8]);
+        Ok(lzma_header)
+    }
 
-            // XXX: do we care about permissions here?  most likely we only care when we are installing for real
-            archive.set_preserve_permissions(true);
-            archive.set_unpack_xattrs(true);
-            archive
-                .unpack(&target_path)
-                .map_err(|e| ArchiveError::Extract(path_to_string(build_path), e))?;
+    // checks the trailer of the member that just ended and moves on to the next member (if any)
+    fn next_member(&mut self) -> io::Result<()> {
+        // the CRC32 and size of the data, followed by the size of the whole member
+        let mut trailer = [0; 20];
+        read_lzip(&mut self.obj, &mut trailer)?;
+        let (crc, size) = (le_u32(&trailer[..4]), le_u64(&trailer[4..12]));
+        if crc != self.crc.sum() || size != self.stream.total_out() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "corrupt lzip member"));
         }
 
-        Ok(file)
+        if self.obj.fill_buf()?.is_empty() {
+            self.done = true;
+        } else {
+            self.header = io::Cursor::new(Self::read_header(&mut self.obj)?);
+            self.stream = Stream::new_lzma_decoder(u64::MAX)?;
+            self.crc = flate2::Crc::new();
+            self.member_done = false;
+        }
+        Ok(())
     }
+}
 
-    fn decompress<T>(&self, build_path: &Path) -> Result<File, ArchiveError>
-    where
-        T: CompDecoder<BufReader<File>>,
-    {
-        let mut file = tempfile::tempfile().map_err(|e| ArchiveError::TempFile(e))?;
-        {
-            let mut writer = BufWriter::new(&mut file);
+impl<R: Read> Read for LzipDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while !self.done && !buf.is_empty() {
+            if self.member_done {
+                self.next_member()?;
+                continue;
+            }
+
+            let in_header = !self.header.fill_buf()?.is_empty();
+            let (read, consumed, status);
+            {
+                let input = if in_header {
+                    self.header.fill_buf()?
+                } else {
+                    self.obj.fill_buf()?
+                };
+                if input.is_empty() {
+                    return Err(truncated_lzip());
+                }
+                let (before_in, before_out) = (self.stream.total_in(), self.stream.total_out());
+                status = self.stream.process(input, buf, Action::Run)?;
+                consumed = (self.stream.total_in() - before_in) as usize;
+                read = (self.stream.total_out() - before_out) as usize;
+            }
+            if in_header {
+                self.header.consume(consumed);
+            } else {
+                self.obj.consume(consumed);
+            }
+            self.crc.update(&buf[..read]);
+
+            self.member_done = status == Status::StreamEnd;
+            if read > 0 {
+                return Ok(read);
+            }
+        }
+        Ok(0)
+    }
+}
+
+// like read_exact(), but with an error that explains what went wrong
+fn read_lzip<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<()> {
+    reader.read_exact(buf).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => truncated_lzip(),
+        _ => e,
+    })
+}
+
+fn truncated_lzip() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "truncated lzip file")
+}
+
+fn is_symlink(path: &Path) -> bool {
+    fs::symlink_metadata(path)
+        .map(|meta| meta.file_type().is_symlink())
+        .unwrap_or(false)
+}
+
+// splits a device number into its major and minor numbers (like the major() and minor() macros)
+fn device_numbers(rdev: u64) -> (u32, u32) {
+    let major = ((rdev >> 8) & 0xfff) | ((rdev >> 32) & !0xfff);
+    let minor = (rdev & 0xff) | ((rdev >> 12) & !0xff);
+    (major as u32, minor as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use libc;
+    use tempfile;
+    use xz2::stream::LzmaOptions;
+    use zip::write::{FileOptions, ZipWriter};
+
+    use std::ffi::CString;
+
+    use checksum::ChecksumKind;
+
+    fn set_mtime(path: &Path, mtime: i64) {
+        let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        let times = [libc::timeval { tv_sec: mtime, tv_usec: 0 }; 2];
+        assert_eq!(unsafe { libc::utimes(path.as_ptr(), times.as_ptr()) }, 0);
+    }
+
+    #[test]
+    fn package_reproducible() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config::test(dir.path());
+        config.source_date_epoch = Some(1234567890);
+        let pkg = BuildFile::parse_test("foo", "  source: []\n").unwrap();
+
+        let pkgdir = pkg.pkg_dir(&config);
+        fs::create_dir_all(pkgdir.join("usr/bin")).unwrap();
+        fs::write(pkgdir.join("usr/bin/foo"), "#!/bin/sh\n").unwrap();
+        fs::write(pkgdir.join("usr/README"), "foo\n").unwrap();
 
-            let input = File::open(build_path)
-                .map_err(|e| ArchiveError::OpenFile(path_to_string(build_path), e))?;
-            let reader = BufReader::new(input);
+        // two builds at different times, both after the source was last changed
+        let mut archives = vec![];
+        for &mtime in &[1500000000, 1600000000] {
+            for path in &["usr/bin/foo", "usr/README", "usr/bin", "usr", ""] {
+                set_mtime(&pkgdir.join(path), mtime);
+            }
+            Archiver::new().package(&config, &pkg).unwrap();
+            archives.push(ChecksumKind::Sha256.hash_file(&pkg.package_path(&config)).unwrap());
+        }
+        assert_eq!(archives[0], archives[1]);
+    }
+
+    #[test]
+    fn install_skips_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config::test(dir.path());
+        let pkg = BuildFile::parse_test("foo", "  source: []\n").unwrap();
+        let pkgdir = pkg.pkg_dir(&config);
+        fs::create_dir_all(pkgdir.join("usr/bin")).unwrap();
+        fs::write(pkgdir.join("usr/bin/foo"), "#!/bin/sh\n").unwrap();
+        Archiver::new().package(&config, &pkg).unwrap();
+
+        let sysroot = dir.path().join("sysroot");
+        fs::create_dir(&sysroot).unwrap();
+        Archiver::new().install(&config, &pkg, &sysroot).unwrap();
+        assert_eq!(fs::read_to_string(sysroot.join("usr/bin/foo")).unwrap(), "#!/bin/sh\n");
+        assert!(!sysroot.join(metadata::PKGINFO).exists());
+        assert!(!sysroot.join(metadata::MTREE).exists());
+    }
 
-            let mut decoder = T::create(reader);
+    #[test]
+    fn compression_names() {
+        let names = [
+            ("none", Compression::None),
+            ("gzip", Compression::Gzip),
+            ("bzip2", Compression::Bzip2),
+            ("xz", Compression::Xz),
+            ("zstd", Compression::Zstd),
+        ];
+        for &(name, compression) in &names {
+            assert_eq!(name.parse(), Ok(compression));
+            assert_eq!(compression.to_string(), name);
+        }
+        assert_eq!(names.iter().map(|&(name, _)| name).collect::<Vec<_>>(), Compression::NAMES);
+        assert_eq!(
+            "lzma".parse::<Compression>(),
+            Err("unknown compression format 'lzma'".to_string())
+        );
+    }
 
-            io::copy(&mut decoder, &mut writer).map_err(|e| ArchiveError::Decompress(e))?;
+    #[test]
+    fn compression_extensions() {
+        let extensions = [
+            (Compression::None, ".tar"),
+            (Compression::Gzip, ".tar.gz"),
+            (Compression::Bzip2, ".tar.bz2"),
+            (Compression::Xz, ".tar.xz"),
+            (Compression::Zstd, ".tar.zst"),
+        ];
+        for &(compression, extension) in &extensions {
+            assert_eq!(compression.extension(), extension);
         }
+    }
+
+    fn text() -> Vec<u8> {
+        (0..5000)
+            .flat_map(|i| format!("line {} of some text\n", i).into_bytes())
+            .collect()
+    }
+
+    // wraps the raw LZMA data written by liblzma's .lzma encoder in an lzip header and trailer
+    fn lzip(data: &[u8]) -> Vec<u8> {
+        let mut options = LzmaOptions::new_preset(6).unwrap();
+        options.dict_size(1 << 16);
+        let stream = Stream::new_lzma_encoder(&options).unwrap();
+        let mut encoder = XzEncoder::new_stream(vec![], stream);
+        encoder.write_all(data).unwrap();
+        let lzma = encoder.finish().unwrap();
+
+        let mut crc = flate2::Crc::new();
+        crc.update(data);
+
+        let mut file = b"LZIP\x01\x10".to_vec();
+        file.extend(&lzma[13..]);
+        let member_size = file.len() as u64 + 20;
+        file.extend((0..4).map(|i| (crc.sum() >> (8 * i)) as u8));
+        file.extend((0..8).map(|i| (data.len() as u64 >> (8 * i)) as u8));
+        file.extend((0..8).map(|i| (member_size >> (8 * i)) as u8));
+        file
+    }
+
+    fn decode(file: &[u8]) -> io::Result<Vec<u8>> {
+        let mut contents = vec![];
+        LzipDecoder::new(file)?.read_to_end(&mut contents)?;
+        Ok(contents)
+    }
+
+    #[test]
+    fn lzip_round_trip() {
+        let data = text();
+        assert_eq!(decode(&lzip(&data)).unwrap(), data);
+    }
+
+    #[test]
+    fn lzip_multiple_members() {
+        let data = text();
+        let mut file = lzip(&data);
+        file.extend(lzip(b"more data"));
+        let mut expected = data.clone();
+        expected.extend(b"more data");
+        assert_eq!(decode(&file).unwrap(), expected);
+    }
+
+    #[test]
+    fn lzip_trailing_data() {
+        let mut file = lzip(&text());
+        file.extend(b"not an lzip member");
+        assert_eq!(decode(&file).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn lzip_corrupt_trailer() {
+        let data = text();
+        // the CRC32 and then the size of the data
+        for &offset in &[20, 16] {
+            let mut file = lzip(&data);
+            let len = file.len();
+            file[len - offset] ^= 1;
+            assert_eq!(decode(&file).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn lzip_unsupported_version() {
+        let mut file = lzip(&text());
+        file[4] = 2;
+        assert_eq!(decode(&file).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn lzip_invalid_dictionary_size() {
+        for &dict in &[0x0b, 0x1e] {
+            let mut file = lzip(&text());
+            file[5] = dict;
+            assert_eq!(decode(&file).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn lzip_truncated() {
+        let file = lzip(&text());
+        let err = decode(&file[..file.len() / 2]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn sniff_lzip() {
+        match sniff_contents(io::Cursor::new(lzip(&text()))).unwrap() {
+            Contents::Compressed(mut decoder, ext) => {
+                let mut contents = vec![];
+                decoder.read_to_end(&mut contents).unwrap();
+                assert_eq!(contents, text());
+                assert_eq!(ext, ".lz");
+            }
+            _ => panic!("lzip file not recognized"),
+        }
+    }
+
+    #[test]
+    fn sniff_text_that_looks_compressed() {
+        for start in &[GZIP_MAGIC, BZIP2_MAGIC, XZ_MAGIC, LZIP_MAGIC, LZMA_MAGIC] {
+            let mut contents = start.to_vec();
+            contents.extend(b" is how this file starts\n");
+            assert!(
+                matches!(sniff_contents(&contents[..]).unwrap(), Contents::Other),
+                "{:?} mistaken for a compressed file",
+                start
+            );
+        }
+    }
+
+    #[test]
+    fn sniff_corrupt_gzip() {
+        // the header is fine, but the first block has an invalid type
+        let mut file = gzip(&text());
+        file[10] |= 0x06;
+        assert!(sniff_contents(&file[..]).is_err());
+    }
+
+    // extracts a package with the given sources (the items of its source list) once the given
+    // files have been "downloaded", returning the package's build directory
+    fn extract_sources(
+        dir: &Path,
+        sources: &[&str],
+        files: &[(&str, &[u8])],
+    ) -> Result<PathBuf, ArchiveError> {
+        let config = Config::test(dir);
+        let mut fields = "  source:\n".to_string();
+        for src in sources {
+            fields.push_str(&format!("    - {}\n", src));
+        }
+        let pkg = BuildFile::parse_test("foo", &fields).unwrap();
+        fs::create_dir_all(pkg.download_dir(&config)).unwrap();
+        for &(name, contents) in files {
+            fs::write(pkg.download_dir(&config).join(name), contents).unwrap();
+        }
+        Archiver::new().extract(&config, &pkg)?;
+        Ok(pkg.archive_out_dir(&config))
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn extract_compressed_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = gzip(&text());
+        let build_dir =
+            extract_sources(dir.path(), &["notes.txt.gz"], &[("notes.txt.gz", &file)]).unwrap();
+        assert_eq!(fs::read(build_dir.join("notes.txt")).unwrap(), text());
+    }
+
+    #[test]
+    fn extract_truncated_gzip() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = gzip(&text());
+        let file = &file[..file.len() / 2];
+        let res = extract_sources(dir.path(), &["notes.txt.gz"], &[("notes.txt.gz", file)]);
+        assert!(matches!(res, Err(ArchiveError::Decompress(..))));
+    }
+
+    #[test]
+    fn extract_text_that_looks_compressed() {
+        let dir = tempfile::tempdir().unwrap();
+        let contents = b"BZh is how this file starts\n";
+        let build_dir =
+            extract_sources(dir.path(), &["notes.txt"], &[("notes.txt", contents)]).unwrap();
+        assert_eq!(fs::read(build_dir.join("notes.txt")).unwrap(), &contents[..]);
+    }
+
+    #[test]
+    fn extract_skips_noextract_sources() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = gzip(&text());
+        let sources = ["url: notes.txt.gz\n      noextract: true"];
+        let build_dir =
+            extract_sources(dir.path(), &sources, &[("notes.txt.gz", &file)]).unwrap();
+        assert_eq!(fs::read(build_dir.join("notes.txt.gz")).unwrap(), file);
+        assert!(!build_dir.join("notes.txt").exists());
+    }
+
+    #[test]
+    fn extract_subdirs_only_where_needed() {
+        let dir = tempfile::tempdir().unwrap();
+        let sources = [
+            "url: fix.patch\n      subdir: patches",
+            "url: notes.txt\n      subdir: docs\n      noextract: true",
+        ];
+        let files: &[(&str, &[u8])] = &[("fix.patch", b"--- a\n+++ b\n"), ("notes.txt", b"hi\n")];
+        let build_dir = extract_sources(dir.path(), &sources, files).unwrap();
+        // patches are applied from download_dir, so they don't need anywhere to go
+        assert!(!build_dir.join("patches").exists());
+        assert_eq!(fs::read(build_dir.join("docs/notes.txt")).unwrap(), b"hi\n");
+    }
+
+    // creates a zip file with the given entries in dir and returns its path
+    fn zip_file(dir: &Path, entries: &[(&str, &str)]) -> PathBuf {
+        let path = dir.join("src.zip");
+        let mut zip = ZipWriter::new(File::create(&path).unwrap());
+        for &(name, contents) in entries {
+            zip.start_file(name, FileOptions::default().unix_permissions(0o640)).unwrap();
+            zip.write_all(contents.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+        path
+    }
+
+    #[test]
+    fn extract_zip_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let zip = zip_file(dir.path(), &[("foo/README", "foo\n"), ("foo/./src/main.c", "")]);
+        let target = dir.path().join("build");
+        Archiver::new().extract_zip(&zip, &target).unwrap();
+
+        assert_eq!(fs::read_to_string(target.join("foo/README")).unwrap(), "foo\n");
+        let meta = fs::metadata(target.join("foo/src/main.c")).unwrap();
+        assert_eq!(meta.mode() & 0o7777, 0o640);
+    }
+
+    #[test]
+    fn extract_zip_rejects_escaping_entries() {
+        for name in &["../x", "/tmp/x", "foo/../../x"] {
+            let dir = tempfile::tempdir().unwrap();
+            let zip = zip_file(dir.path(), &[(name, "escaped\n")]);
+            let target = dir.path().join("build");
+            let res = Archiver::new().extract_zip(&zip, &target);
+            assert!(matches!(res, Err(ArchiveError::UnsafePath(_, ref path)) if path == name));
+            assert!(!dir.path().join("x").exists());
+        }
+    }
+
+    // unpacks an entry the way extract_zip() and extract_7z() do
+    fn unpack(
+        target: &Path,
+        name: &str,
+        mode: Option<u32>,
+        contents: &str,
+    ) -> Result<(), ArchiveError> {
+        let archive = Path::new("src.7z");
+        let mut reader = contents.as_bytes();
+        Archiver::new().unpack_entry(archive, target, Path::new(name), false, mode, &mut reader)
+    }
+
+    #[test]
+    fn unpack_entry_rejects_escaping_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("build");
+        for name in &["../x", "/tmp/x", "foo/../../x"] {
+            let res = unpack(&target, name, None, "escaped\n");
+            assert!(matches!(res, Err(ArchiveError::UnsafePath(_, ref path)) if path == name));
+        }
+        assert!(!dir.path().join("x").exists());
+    }
+
+    #[test]
+    fn unpack_entry_rejects_escaping_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("build");
+        let link = Some(libc::S_IFLNK | 0o777);
+        unpack(&target, "foo/lib", link, "../lib").unwrap();
+        assert_eq!(fs::read_link(target.join("foo/lib")).unwrap(), Path::new("../lib"));
 
-        file.seek(SeekFrom::Start(0))
-            .map_err(|e| ArchiveError::Decompress(e))?;
+        let res = unpack(&target, "foo/up", link, "../../x");
+        assert!(matches!(res, Err(ArchiveError::UnsafeLink(_, ref path, _)) if path == "foo/up"));
+        let res = unpack(&target, "foo/root", link, "/etc");
+        assert!(matches!(res, Err(ArchiveError::UnsafeLink(_, ref path, _)) if path == "foo/root"));
 
-        Ok(file)
+        // a later entry can't be written through a symlink created by an earlier one
+        let res = unpack(&target, "foo/lib/x", None, "escaped\n");
+        assert!(matches!(
+            res,
+            Err(ArchiveError::InsideSymlink(_, ref path, _)) if path == "foo/lib/x"
+        ));
+        assert!(!target.join("lib").exists());
     }
 }
@@ -2,15 +2,20 @@ use failure::Error;
 use indicatif::{ProgressBar, ProgressStyle};
 use term_size;
 
+use std::collections::HashSet;
 use std::fs::{self, File};
 use std::io::{self, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
+use std::sync::Mutex;
 
 use archive::{ArchiveError, Archiver};
 use config::Config;
-use package::BuildFile;
+use fakeroot::{self, FakerootError};
+use metadata;
+use package::{BuildFile, PackageOption};
 use progress::{InitFn, IterFn};
+use sandbox::{self, Sandbox, SandboxError};
 use util::{self, path_to_string};
 
 #[derive(Debug, Fail)]
@@ -18,6 +23,12 @@ pub enum BuildError {
     #[fail(display = "{}", _0)]
     Archive(#[cause] ArchiveError),
 
+    #[fail(display = "{}", _0)]
+    Sandbox(#[cause] SandboxError),
+
+    #[fail(display = "{}", _0)]
+    Fakeroot(#[cause] FakerootError),
+
     #[fail(display = "could not remove directory '{}': {}", _0, _1)]
     RemoveDir(String, #[cause] io::Error),
 
@@ -33,6 +44,9 @@ pub enum BuildError {
     #[fail(display = "could not execute command '{}': {}", _0, _1)]
     Spawn(String, #[cause] io::Error),
 
+    #[fail(display = "could not isolate the commands of '{}'", _0)]
+    Isolate(String),
+
     #[fail(display = "issue waiting for command '{}' to exit: {}", _0, _1)]
     Wait(String, #[cause] io::Error),
 
@@ -42,11 +56,17 @@ pub enum BuildError {
     #[fail(display = "could not write to stdin for '{}': {}", _0, _1)]
     WriteChild(String, #[cause] io::Error),
 
+    #[fail(display = "could not apply patch '{}' as directory '{}' does not exist", _0, _1)]
+    PatchDir(String, String),
+
     #[fail(display = "package '{}' failed on command '{}' with {:?}", _0, _1, _2)]
     Command(String, String, Option<i32>),
 }
 
-pub struct Builder {}
+pub struct Builder {
+    // whether commands can be run in namespaces of their own (None until we have checked)
+    isolation: Mutex<Option<bool>>,
+}
 
 // XXX: it might be cool to have a build progress system based on a reference computer
 //      for example, the system could compare the difference in build speed between the user's
@@ -59,13 +79,15 @@ pub struct Builder {}
 //      at the end (and maybe the amount of time it has taken so far during the build)
 impl Builder {
     pub fn new() -> Self {
-        Self {}
+        Self {
+            isolation: Mutex::new(None),
+        }
     }
 
     pub fn build_setup<'a>(
         &'a self,
         _config: &Config,
-        pkgs: &[BuildFile],
+        pkgs: &'a [BuildFile],
     ) -> (Box<InitFn<'a>>, Box<IterFn<'a>>) {
         let pkgslen = pkgs.len();
 
@@ -92,11 +114,22 @@ impl Builder {
                     .extract(config, pkg)
                     .map_err(|e| BuildError::Archive(e))?;
 
+                if pkg.uses_sysroot(config) {
+                    progbar.set_message("populating sysroot");
+                    self.populate_sysroot(config, pkg, &archiver, pkgs)?;
+                }
+
+                // the install step runs as (fake) root with changes in ownership being recorded
+                // (unless we really are root)
+                let fakeroot_env =
+                    fakeroot::setup(config, pkg).map_err(BuildError::Fakeroot)?;
+
+                let install_env = fakeroot_env.as_ref().map(|env| &env[..]);
                 let steps = &[
-                    (pkg.download_dir(config), pkg.prepare()),
-                    (pkg.archive_out_dir(config), pkg.build()),
-                    (pkg.archive_out_dir(config), pkg.check()),
-                    (pkg.archive_out_dir(config), pkg.install()),
+                    (pkg.download_dir(config), pkg.prepare(), None),
+                    (pkg.archive_out_dir(config), pkg.build(), None),
+                    (pkg.archive_out_dir(config), pkg.check(), None),
+                    (pkg.archive_out_dir(config), pkg.install(), install_env),
                 ];
 
                 // FIXME: verbose mode doesn't work well as it interferes with the progress bar (perhaps
@@ -126,13 +159,16 @@ impl Builder {
                 fs::create_dir(&pkgdir)
                     .map_err(|e| BuildError::CreateDir(path_to_string(&pkgdir), e))?;
 
-                for (cur_dir, step) in steps {
+                self.apply_patches(progbar, config, pkg, stdout.as_ref(), stderr.as_ref())?;
+
+                for (cur_dir, step, fakeroot_env) in steps {
                     self.run_step(
                         progbar,
                         config,
                         pkg,
                         &cur_dir,
                         *step,
+                        *fakeroot_env,
                         stdout.as_ref(),
                         stderr.as_ref(),
                     )?;
@@ -150,6 +186,83 @@ impl Builder {
         (Box::new(init_fn), Box::new(iter_fn))
     }
 
+    // extracts the packages built for every dependency of the given package (along with their own
+    // runtime dependencies) into a fresh root filesystem for the package's sandbox
+    fn populate_sysroot(
+        &self,
+        config: &Config,
+        pkg: &BuildFile,
+        archiver: &Archiver,
+        pkgs: &[BuildFile],
+    ) -> Result<(), BuildError> {
+        let sysroot = pkg.sysroot_dir(config);
+        if sysroot.exists() {
+            fs::remove_dir_all(&sysroot)
+                .map_err(|e| BuildError::RemoveDir(path_to_string(&sysroot), e))?;
+        }
+        fs::create_dir_all(&sysroot)
+            .map_err(|e| BuildError::CreateDir(path_to_string(&sysroot), e))?;
+
+        let mut needed = HashSet::new();
+        let mut pending = pkg.build_depends();
+        while let Some(name) = pending.pop() {
+            if needed.insert(name) {
+                if let Some(dep) = pkgs.iter().find(|dep| dep.name() == name) {
+                    pending.extend(dep.runtime_depends());
+                }
+            }
+        }
+
+        // the packages are sorted such that dependencies come first, so files from a package
+        // always take precedence over those from the packages it depends on
+        for dep in pkgs.iter().filter(|dep| needed.contains(dep.name())) {
+            archiver
+                .install(config, dep, &sysroot)
+                .map_err(BuildError::Archive)?;
+        }
+
+        Ok(())
+    }
+
+    // runs patch(1) for each of the package's patches (in order) within the build directory
+    fn apply_patches(
+        &self,
+        progbar: &ProgressBar,
+        config: &Config,
+        pkg: &BuildFile,
+        stdout: Option<&File>,
+        stderr: Option<&File>,
+    ) -> Result<(), BuildError> {
+        let builddir = pkg.archive_out_dir(config);
+        for patch in pkg.patches() {
+            let dir = match patch.dir() {
+                Some(dir) => builddir.join(dir),
+                None => {
+                    // most tarballs unpack into a directory named after the package
+                    let srcroot = builddir.join(format!("{}-{}", pkg.name(), pkg.version()));
+                    if srcroot.is_dir() {
+                        srcroot
+                    } else {
+                        builddir.clone()
+                    }
+                }
+            };
+            if !dir.is_dir() {
+                Err(BuildError::PatchDir(patch.file().to_string(), path_to_string(&dir)))?;
+            }
+
+            // -N keeps patch from asking whether to reverse patches that seem to be applied
+            let cmd = format!(
+                "patch -Np{} -i \"$srcdir\"/{}",
+                patch.strip(),
+                util::shell_quote(patch.file())
+            );
+            self.run_step(progbar, config, pkg, &dir, Some(&vec![cmd]), None, stdout, stderr)?;
+        }
+
+        Ok(())
+    }
+
     fn run_step(
         &self,
         progbar: &ProgressBar,
@@ -157,6 +270,7 @@ impl Builder {
         pkg: &BuildFile,
         cur_dir: &Path,
         step: Option<&Vec<String>>,
+        fakeroot_env: Option<&[(String, PathBuf)]>,
         stdout: Option<&File>,
         stderr: Option<&File>,
     ) -> Result<(), BuildError> {
@@ -195,22 +309,20 @@ impl Builder {
                     (None, None)
                 };
 
-                self.run_command(config, pkg, cmd, cur_dir, stdout, stderr)?;
+                self.run_command(config, pkg, cmd, cur_dir, fakeroot_env, stdout, stderr)?;
             }
         }
 
         Ok(())
     }
 
-    // TODO: isolate the commands in chroot/namespace (this would require mounting any dependencies
-    //       in the chrooted/namespaced environment), which means we would need to specify
-    //       dependencies in the build file
     fn run_command(
         &self,
         config: &Config,
         pkg: &BuildFile,
         cmd: &str,
         cur_dir: &Path,
+        fakeroot_env: Option<&[(String, PathBuf)]>,
         stdout: Option<File>,
         stderr: Option<File>,
     ) -> Result<(), BuildError> {
@@ -226,6 +338,8 @@ impl Builder {
         // TODO: load user-specified default env vars from a file
         //       should replace the below
         sh.env("MAKEFLAGS", format!("-j{}", util::cpu_count()));
+        // lets tools that embed timestamps use the same one we use for the package
+        sh.env("SOURCE_DATE_EPOCH", metadata::build_date(config).to_string());
         sh.envs(pkg.env());
 
         let pkgdir = pkg.pkg_dir(config);
@@ -249,6 +363,38 @@ impl Builder {
                 .canonicalize()
                 .map_err(|e| BuildError::Canonicalize(path_to_string(&srcdir), e))?,
         );
+        if let Some(fakeroot_env) = fakeroot_env {
+            sh.envs(fakeroot_env.iter().cloned());
+        }
+
+        // everything the build needs should have been fetched by the download step
+        let network = pkg.has_option(PackageOption::Network) || config.allow_network;
+        let mut isolated = needs_isolation(config.sandbox, network, fakeroot_env.is_some());
+        if isolated && !self.can_isolate()? {
+            // the install step can't appear to run as root without a user namespace
+            if fakeroot_env.is_some() {
+                Err(BuildError::Isolate(pkg.name().to_string()))?;
+            }
+            isolated = false;
+        }
+        if config.sandbox {
+            // the build can see the rest of the system but only write to its own directories
+            let mut sandbox = Sandbox::new(pkg.root_dir(config), pkg.sandbox_dir(config));
+            sandbox
+                .add_writable(&srcdir)
+                .add_writable(&builddir)
+                .add_writable(&pkgdir)
+                .set_network(network);
+            if fakeroot_env.is_some() {
+                sandbox.add_writable(pkg.fakeroot_dir(config));
+            }
+            sandbox
+                .apply(&mut sh, cur_dir)
+                .map_err(BuildError::Sandbox)?;
+        } else if isolated {
+            sandbox::isolate(&mut sh, network, fakeroot_env.is_some());
+        }
+
         let mut child = sh.current_dir(cur_dir)
             .stdin(Stdio::piped())
             .spawn()
@@ -277,9 +423,60 @@ impl Builder {
         }
     }
 
+    // checks whether commands can be isolated the first time it is needed, warning if they can't
+    // (in which case they run without being cut off from the network)
+    fn can_isolate(&self) -> Result<bool, BuildError> {
+        let mut isolation = self.isolation.lock().unwrap();
+        if let Some(supported) = *isolation {
+            return Ok(supported);
+        }
+
+        let supported = sandbox::isolation_supported()
+            .map_err(|e| BuildError::Spawn("/bin/sh".to_string(), e))?;
+        if !supported {
+            let _ = util::display_warn(format_args!(
+                "build commands will have network access as namespaces could not be created \
+                 (pass --allow-network to skip trying)"
+            ));
+        }
+        *isolation = Some(supported);
+        Ok(supported)
+    }
+
     fn spinner_style(&self) -> ProgressStyle {
         ProgressStyle::default_spinner()
             .tick_chars(r"/|\- ")
             .template("{prefix:.bold.dim}: {spinner} [{msg}]")
     }
 }
+
+// whether a command run outside of a sandbox (which provides its own namespaces) has to be given
+// namespaces of its own, either to cut it off from the network or so that the install step can
+// appear to run as root
+fn needs_isolation(sandbox: bool, network: bool, fakeroot: bool) -> bool {
+    !sandbox && (!network || fakeroot)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn isolated_without_network_option() {
+        assert!(needs_isolation(false, false, false));
+        assert!(!needs_isolation(false, true, false));
+    }
+
+    #[test]
+    fn install_step_always_isolated() {
+        assert!(needs_isolation(false, false, true));
+        assert!(needs_isolation(false, true, true));
+    }
+
+    #[test]
+    fn sandbox_isolates_itself() {
+        for &(network, fakeroot) in &[(false, false), (true, false), (false, true), (true, true)] {
+            assert!(!needs_isolation(true, network, fakeroot));
+        }
+    }
+}
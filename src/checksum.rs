@@ -0,0 +1,416 @@
+use blake2::Blake2b;
+use sha2::{Digest, Sha256};
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+use config::Config;
+use package::{BuildFile, PackageError, Source};
+use util::path_to_string;
+
+#[derive(Debug, Fail)]
+pub enum ChecksumError {
+    #[fail(display = "could not read build file '{}': {}", _0, _1)]
+    ReadBuildFile(String, #[cause] io::Error),
+
+    #[fail(display = "could not write build file '{}': {}", _0, _1)]
+    WriteBuildFile(String, #[cause] io::Error),
+
+    #[fail(display = "could not compute checksum of '{}': {}", _0, _1)]
+    Hash(String, #[cause] io::Error),
+
+    #[fail(display = "could not find the package section in '{}'", _0)]
+    MissingPackage(String),
+
+    #[fail(display = "{}", _0)]
+    Package(#[cause] PackageError),
+}
+
+// placeholder used in place of a checksum for sources that cannot (or should not) be verified,
+// such as git repositories
+pub const SKIP: &str = "SKIP";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChecksumKind {
+    Sha256,
+    Blake2b,
+}
+
+impl ChecksumKind {
+    // the name of the field in the build file that holds this type of checksum
+    pub fn field_name(&self) -> &'static str {
+        match *self {
+            ChecksumKind::Sha256 => "sha256sums",
+            ChecksumKind::Blake2b => "b2sums",
+        }
+    }
+
+    // the name of the directory in the source cache holding files with this type of checksum
+    pub fn cache_name(&self) -> &'static str {
+        match *self {
+            ChecksumKind::Sha256 => "sha256",
+            ChecksumKind::Blake2b => "b2",
+        }
+    }
+
+    pub fn hash_file<P: AsRef<Path> + ?Sized>(&self, path: &P) -> io::Result<String> {
+        let reader = BufReader::new(File::open(path)?);
+        match *self {
+            ChecksumKind::Sha256 => hash_reader::<Sha256, _>(reader),
+            ChecksumKind::Blake2b => hash_reader::<Blake2b, _>(reader),
+        }
+    }
+
+    pub fn hash_bytes(&self, data: &[u8]) -> String {
+        // reading from memory can't fail
+        match *self {
+            ChecksumKind::Sha256 => hash_reader::<Sha256, _>(data).unwrap(),
+            ChecksumKind::Blake2b => hash_reader::<Blake2b, _>(data).unwrap(),
+        }
+    }
+}
+
+impl fmt::Display for ChecksumKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            ChecksumKind::Sha256 => "SHA-256",
+            ChecksumKind::Blake2b => "BLAKE2b",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+fn hash_reader<D: Digest, R: Read>(mut reader: R) -> io::Result<String> {
+    const BUF_SIZE: usize = 32 * 1024;
+
+    let mut hasher = D::new();
+    let mut buffer = [0; BUF_SIZE];
+    loop {
+        let n = reader.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.input(&buffer[..n]);
+    }
+
+    Ok(hasher
+        .result()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+// hashes the downloaded sources of the given package and writes the results back into its build
+// file.  checksum types that are already present are regenerated (defaulting to SHA-256 if there
+// are none)
+pub fn update_buildfile(config: &Config, pkg: &BuildFile) -> Result<(), ChecksumError> {
+    let mut kinds = pkg.checksum_kinds();
+    if kinds.is_empty() {
+        kinds.push(ChecksumKind::Sha256);
+    }
+
+    let build_path = pkg.pkgbuild_dir(config).join(pkg.path());
+    let mut contents = fs::read_to_string(&build_path)
+        .map_err(|e| ChecksumError::ReadBuildFile(path_to_string(&build_path), e))?;
+
+    for kind in kinds {
+        let mut sums = vec![];
+        for src in pkg.source() {
+            sums.push(compute_checksum(config, pkg, kind, src)?);
+        }
+
+        contents = replace_list(&contents, kind.field_name(), &sums)
+            .ok_or_else(|| ChecksumError::MissingPackage(path_to_string(&build_path)))?;
+    }
+
+    fs::write(&build_path, contents)
+        .map_err(|e| ChecksumError::WriteBuildFile(path_to_string(&build_path), e))
+}
+
+fn compute_checksum(
+    config: &Config,
+    pkg: &BuildFile,
+    kind: ChecksumKind,
+    src: &Source,
+) -> Result<String, ChecksumError> {
+    if src.is_git() {
+        return Ok(SKIP.to_string());
+    }
+
+    let path = pkg.file_download_path(config, src)
+        .map_err(ChecksumError::Package)?;
+    if path.is_dir() {
+        // local directories are copied as-is, so there is nothing sensible to hash
+        Ok(SKIP.to_string())
+    } else {
+        kind.hash_file(&path)
+            .map_err(|e| ChecksumError::Hash(path_to_string(&path), e))
+    }
+}
+
+// replaces (or inserts after the source list) the list stored under `key` in the package section
+// of a build file.  this works on the raw lines rather than going through serde_yaml so that
+// everything else in the file (comments, ordering, formatting) stays exactly as it was
+fn replace_list(contents: &str, key: &str, values: &[String]) -> Option<String> {
+    let lines: Vec<&str> = contents.lines().collect();
+
+    let pkg_line = lines.iter().position(|line| line.trim() == "package:")?;
+    let pkg_indent = indentation(lines[pkg_line]);
+
+    // find where the package section ends and how far its keys are indented
+    let mut key_indent = None;
+    let mut pkg_end = lines.len();
+    for (i, line) in lines.iter().enumerate().skip(pkg_line + 1) {
+        if is_blank(line) {
+            continue;
+        }
+        let indent = indentation(line);
+        if indent <= pkg_indent {
+            pkg_end = i;
+            break;
+        }
+        if key_indent.is_none() {
+            key_indent = Some(indent);
+        }
+    }
+    let key_indent = key_indent?;
+
+    let find_key = |name: &str| {
+        let prefix = format!("{}:", name);
+        (pkg_line + 1..pkg_end).find(|&i| {
+            indentation(lines[i]) == key_indent && lines[i].trim_start().starts_with(&prefix)
+        })
+    };
+
+    let (start, end, item_indent) = match find_key(key) {
+        Some(start) => {
+            let (last, item_indent) = value_end(&lines, start, key_indent, pkg_end);
+            (start, last + 1, item_indent)
+        }
+        None => {
+            // put the checksums right after the sources (using the same style of indentation)
+            let (pos, item_indent) = match find_key("source") {
+                Some(src_line) => {
+                    let (last, item_indent) = value_end(&lines, src_line, key_indent, pkg_end);
+                    (last + 1, item_indent)
+                }
+                None => (pkg_end, None),
+            };
+            (pos, pos, item_indent)
+        }
+    };
+    let item_indent = item_indent.unwrap_or(key_indent + 2);
+
+    let mut replacement = vec![];
+    if values.is_empty() {
+        replacement.push(format!("{}{}: []", " ".repeat(key_indent), key));
+    } else {
+        replacement.push(format!("{}{}:", " ".repeat(key_indent), key));
+        for value in values {
+            replacement.push(format!("{}- {}", " ".repeat(item_indent), quote(value)));
+        }
+    }
+
+    let mut result: Vec<String> = lines[..start].iter().map(|line| line.to_string()).collect();
+    result.extend(replacement);
+    result.extend(lines[end..].iter().map(|line| line.to_string()));
+
+    let mut output = result.join("\n");
+    if contents.ends_with('\n') {
+        output.push('\n');
+    }
+    Some(output)
+}
+
+// returns the index of the last line belonging to the value of the key at the given line along
+// with the indentation used for list items (if the value is a list)
+fn value_end(
+    lines: &[&str],
+    key_line: usize,
+    key_indent: usize,
+    section_end: usize,
+) -> (usize, Option<usize>) {
+    let mut last = key_line;
+    let mut item_indent = None;
+    for (i, line) in lines.iter().enumerate().take(section_end).skip(key_line + 1) {
+        if is_blank(line) {
+            continue;
+        }
+
+        let indent = indentation(line);
+        let is_item = line.trim_start().starts_with('-');
+        if indent > key_indent || (indent == key_indent && is_item) {
+            if is_item && item_indent.is_none() {
+                item_indent = Some(indent);
+            }
+            last = i;
+        } else {
+            break;
+        }
+    }
+    (last, item_indent)
+}
+
+fn indentation(line: &str) -> usize {
+    line.len() - line.trim_start_matches(' ').len()
+}
+
+fn is_blank(line: &str) -> bool {
+    let line = line.trim();
+    line.is_empty() || line.starts_with('#')
+}
+
+// checksums are hex strings, but a YAML parser would read an all-digit one as a number
+fn quote(value: &str) -> String {
+    if value.parse::<f64>().is_ok() {
+        format!("'{}'", value)
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sums(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn replace_existing_list() {
+        let contents = "\
+# a comment
+package:
+  name: foo
+  source:
+    - http://example.com/foo.tar.gz
+  sha256sums:
+    - aaaa
+  build:
+    - make
+";
+        let expected = "\
+# a comment
+package:
+  name: foo
+  source:
+    - http://example.com/foo.tar.gz
+  sha256sums:
+    - bbbb
+  build:
+    - make
+";
+        assert_eq!(
+            replace_list(contents, "sha256sums", &sums(&["bbbb"])).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn insert_after_source() {
+        let contents = "\
+package:
+  name: foo
+  source:
+  - http://example.com/foo.tar.gz
+
+  - files/config
+  build:
+  - make";
+        let expected = "\
+package:
+  name: foo
+  source:
+  - http://example.com/foo.tar.gz
+
+  - files/config
+  b2sums:
+  - aaaa
+  - bbbb
+  build:
+  - make";
+        assert_eq!(
+            replace_list(contents, "b2sums", &sums(&["aaaa", "bbbb"])).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn insert_after_renamed_sources() {
+        let contents = "\
+package:
+  name: foo
+  source:
+  - foo-1.0.tar.gz::http://example.com/download?id=1
+  - url: http://example.com/bar/
+    filename: bar.tar.gz
+    subdir: bar
+  build:
+  - make";
+        let expected = "\
+package:
+  name: foo
+  source:
+  - foo-1.0.tar.gz::http://example.com/download?id=1
+  - url: http://example.com/bar/
+    filename: bar.tar.gz
+    subdir: bar
+  sha256sums:
+  - aaaa
+  - bbbb
+  build:
+  - make";
+        assert_eq!(
+            replace_list(contents, "sha256sums", &sums(&["aaaa", "bbbb"])).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn insert_without_source() {
+        let contents = "package:\n    name: foo\nenv:\n    FOO: bar\n";
+        let expected = "package:\n    name: foo\n    sha256sums: []\nenv:\n    FOO: bar\n";
+        assert_eq!(replace_list(contents, "sha256sums", &[]).unwrap(), expected);
+    }
+
+    #[test]
+    fn quote_numeric_checksums() {
+        let contents = "package:\n  sha256sums: []\n";
+        let expected = "package:\n  sha256sums:\n    - '1234'\n    - 12ab\n";
+        assert_eq!(
+            replace_list(contents, "sha256sums", &sums(&["1234", "12ab"])).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn replace_without_package() {
+        assert_eq!(replace_list("env:\n  FOO: bar\n", "sha256sums", &[]), None);
+        assert_eq!(replace_list("package:\n", "sha256sums", &[]), None);
+    }
+
+    #[test]
+    fn value_end_of_lists() {
+        let lines = vec![
+            "package:",
+            "  source:",
+            "    - a",
+            "    # comment",
+            "    - url: b",
+            "      filename: c",
+            "",
+            "  build:",
+            "  - make",
+            "  install: []",
+        ];
+        assert_eq!(value_end(&lines, 1, 2, lines.len()), (5, Some(4)));
+        // items may be indented as far as the key itself
+        assert_eq!(value_end(&lines, 7, 2, lines.len()), (8, Some(2)));
+        assert_eq!(value_end(&lines, 9, 2, lines.len()), (9, None));
+        // nothing past the end of the section is looked at
+        assert_eq!(value_end(&lines, 1, 2, 3), (2, Some(4)));
+    }
+}
@@ -1,11 +1,18 @@
 use clap::OsValues;
-use failure::Error;
+use failure::{Error, ResultExt};
+use serde_yaml;
 
+use std::collections::HashMap;
 use std::ffi::{OsStr, OsString};
 use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
 use std::path::Path;
 
+use archive::Compression;
 use builder::Builder;
+use checksum;
+use dependency;
 use network::Downloader;
 use package::BuildFile;
 use progress::Progress;
@@ -19,6 +26,9 @@ pub enum Action<'a> {
 
     // print a short description of a given package
     Describe { pkgs: OsValues<'a> },
+
+    // download the package and regenerate the checksums in its build file
+    UpdateSums { pkgs: OsValues<'a> },
 }
 
 impl<'a> Action<'a> {
@@ -27,7 +37,7 @@ impl<'a> Action<'a> {
 
         match self {
             Download { pkgs } => {
-                let buildfiles = self.gather_buildfiles(config, pkgs)?;
+                let buildfiles = self.resolve_buildfiles(config, pkgs)?;
 
                 let downloader = Downloader::new();
                 let (init, iter) = downloader.download_setup(config, &buildfiles);
@@ -37,7 +47,7 @@ impl<'a> Action<'a> {
                     .run(config, buildfiles.iter())?;
             }
             Build { pkgs } => {
-                let buildfiles = self.gather_buildfiles(config, pkgs)?;
+                let buildfiles = self.resolve_buildfiles(config, pkgs)?;
 
                 let downloader = Downloader::new();
                 let builder = Builder::new();
@@ -48,6 +58,7 @@ impl<'a> Action<'a> {
                 Progress::new(config, &buildfiles)
                     .add_step(&*download_init, &*download_iter)
                     .add_step(&*build_init, &*build_iter)
+                    .order_by_dependencies()
                     .run(config, buildfiles.iter())?;
             }
             Describe { pkgs } => {
@@ -62,6 +73,22 @@ impl<'a> Action<'a> {
                     println!("\n{}", buildfile.info());
                 }
             }
+            UpdateSums { pkgs } => {
+                let buildfiles = self.gather_buildfiles(config, pkgs)?;
+
+                // the whole point is to replace the checksums, so they are most likely outdated
+                let mut downloader = Downloader::new();
+                downloader.set_verify(false);
+                let (init, iter) = downloader.download_setup(config, &buildfiles);
+
+                Progress::new(config, &buildfiles)
+                    .add_step(&*init, &*iter)
+                    .run(config, buildfiles.iter())?;
+
+                for buildfile in &buildfiles {
+                    checksum::update_buildfile(config, buildfile)?;
+                }
+            }
         }
 
         Ok(())
@@ -76,6 +103,17 @@ impl<'a> Action<'a> {
             .map(|pkg| BuildFile::open(config.pkgbuild_dir, pkg))
             .collect()
     }
+
+    // like gather_buildfiles(), but also pulls in dependencies and sorts the packages such that
+    // dependencies come first
+    fn resolve_buildfiles(
+        &self,
+        config: &Config,
+        pkgs: &OsValues,
+    ) -> Result<Vec<BuildFile>, Error> {
+        let buildfiles = self.gather_buildfiles(config, pkgs)?;
+        dependency::resolve(config, buildfiles)
+    }
 }
 
 impl<'a> fmt::Debug for Action<'a> {
@@ -86,6 +124,7 @@ impl<'a> fmt::Debug for Action<'a> {
             Download { .. } => "Download",
             Build { .. } => "Build",
             Describe { .. } => "Describe",
+            UpdateSums { .. } => "UpdateSums",
         };
         write!(f, "{}", action)
     }
@@ -100,7 +139,70 @@ pub struct Config<'a> {
     pub verbose: bool,
     pub clobber: bool,
     pub fail_fast: bool,
+    // never connect to the network, using only sources that have already been downloaded
+    pub offline: bool,
+    // let build commands access the network even if their package doesn't ask for it
+    pub allow_network: bool,
+    pub sandbox: bool,
+    pub toolchain_root: &'a Path,
+    pub sysroot: bool,
+    // timestamp used in place of the current time to make builds reproducible
+    pub source_date_epoch: Option<u64>,
+    // directory shared by all packages in which downloaded sources are kept
+    pub cache_dir: &'a Path,
+    // directory of public keys used to check the signatures of sources
+    pub keyring_dir: &'a Path,
+    // base URLs of each named set of mirrors (e.g. "gnu" for gnu://path/to/file)
+    pub mirrors: HashMap<String, Vec<String>>,
+    // format used for packages that don't specify their own
+    pub compression: Compression,
     pub parallel_build: Option<u32>,
     pub parallel_download: Option<u32>,
+    // how many times to retry an HTTP download that failed for reasons that might go away
+    pub retries: u32,
     pub action: Action<'a>,
 }
+
+impl<'a> Config<'a> {
+    // a configuration for tests that reads build files from and writes everything to the given
+    // directory
+    #[cfg(test)]
+    pub fn test(dir: &'a Path) -> Self {
+        Config {
+            pkgbuild_dir: dir,
+            build_dir: dir,
+            licenses: vec![],
+            verbose: false,
+            clobber: false,
+            fail_fast: false,
+            offline: false,
+            allow_network: false,
+            sandbox: false,
+            toolchain_root: Path::new("/"),
+            sysroot: false,
+            source_date_epoch: None,
+            cache_dir: dir,
+            keyring_dir: dir,
+            mirrors: HashMap::new(),
+            compression: Compression::Xz,
+            parallel_build: None,
+            parallel_download: None,
+            retries: 0,
+            action: Action::Describe {
+                pkgs: OsValues::default(),
+            },
+        }
+    }
+}
+
+// reads a file mapping the names of mirror sets to lists of base URLs, such as
+//
+//     gnu:
+//       - https://ftp.gnu.org/gnu
+//       - https://mirrors.kernel.org/gnu
+pub fn load_mirrors(path: &Path) -> Result<HashMap<String, Vec<String>>, Error> {
+    let file = File::open(path).with_context(|err| {
+        format!("could not read mirrors file at '{}': {}", path.display(), err)
+    })?;
+    Ok(serde_yaml::from_reader(BufReader::new(file))?)
+}
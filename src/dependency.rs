@@ -0,0 +1,387 @@
+use failure::Error;
+use walkdir::Error as WalkError;
+use walkdir::{DirEntry, WalkDir};
+
+use std::collections::{btree_map, hash_map};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use config::Config;
+use package::BuildFile;
+use progress::AggregateError;
+use util::{self, path_to_string};
+
+#[derive(Debug, Fail)]
+pub enum DependencyError {
+    #[fail(display = "could not get real path for '{}': {}", _0, _1)]
+    Canonicalize(String, #[cause] io::Error),
+
+    #[fail(display = "found invalid directory entry while searching for build files: {}", _0)]
+    DirEntry(#[cause] WalkError),
+
+    #[fail(display = "'{}' depends on '{}', which is not in the pkgbuild directory", _0, _1)]
+    Unknown(String, String),
+
+    #[fail(display = "found dependency cycle {} (consider marking a dependency as bootstrap)",
+           _0)]
+    Cycle(String),
+}
+
+// adds the dependencies of the given packages (as well as their dependencies and so on) that can
+// be found in the pkgbuild directory and then sorts the packages so that dependencies always come
+// before the packages that need them
+pub fn resolve(config: &Config, buildfiles: Vec<BuildFile>) -> Result<Vec<BuildFile>, Error> {
+    let mut pkgs = by_name(buildfiles);
+
+    // only search through the pkgbuild directory if we actually need to
+    let mut index = None;
+
+    let mut unknown = vec![];
+
+    // pairs of (package, dependency)
+    let mut pending: Vec<(String, String)> = pkgs.values().flat_map(dependency_pairs).collect();
+    while let Some((name, dep)) = pending.pop() {
+        if pkgs.contains_key(&dep) {
+            continue;
+        }
+
+        if index.is_none() {
+            index = Some(index_pkgbuild_dir(config)?);
+        }
+
+        if let Some(pkg) = index.as_mut().unwrap().remove(&dep) {
+            pending.extend(dependency_pairs(&pkg));
+            pkgs.insert(dep, pkg);
+        } else {
+            unknown.push(DependencyError::Unknown(name, dep).into());
+        }
+    }
+
+    if !unknown.is_empty() {
+        Err(AggregateError { errs: unknown })?;
+    }
+
+    Ok(sort(pkgs)?)
+}
+
+// maps the names of the given packages to the packages, keeping only the first build file given
+// for each package
+fn by_name(buildfiles: Vec<BuildFile>) -> BTreeMap<String, BuildFile> {
+    let mut pkgs: BTreeMap<String, BuildFile> = BTreeMap::new();
+    for pkg in buildfiles {
+        match pkgs.entry(pkg.name().to_string()) {
+            btree_map::Entry::Occupied(entry) => {
+                // giving the same build file twice is harmless, but a second one for the package
+                // is not going to be built
+                let first = entry.get();
+                if first.path() != pkg.path() {
+                    let _ = util::display_warn(format_args!(
+                        "skipping '{}': package '{}' is already defined in '{}'",
+                        pkg.path().display(),
+                        pkg.name(),
+                        first.path().display()
+                    ));
+                }
+            }
+            btree_map::Entry::Vacant(entry) => {
+                entry.insert(pkg);
+            }
+        }
+    }
+    pkgs
+}
+
+fn dependency_pairs(pkg: &BuildFile) -> Vec<(String, String)> {
+    pkg.build_depends()
+        .into_iter()
+        .map(|dep| (pkg.name().to_string(), dep.to_string()))
+        .collect()
+}
+
+// reads every build file in the pkgbuild directory, returning a map of package names to packages
+fn index_pkgbuild_dir(config: &Config) -> Result<HashMap<String, BuildFile>, DependencyError> {
+    // we need to make sure we don't search through the build directory as extracted sources can
+    // easily contain files named BUILD
+    let build_dir = if config.build_dir.exists() {
+        Some(canonicalize(config.build_dir)?)
+    } else {
+        None
+    };
+    let is_build_dir = |entry: &DirEntry| {
+        entry.file_type().is_dir() && entry.path().canonicalize().ok() == build_dir
+    };
+    let is_hidden = |entry: &DirEntry| {
+        entry.depth() > 0 && entry.file_name().to_string_lossy().starts_with('.')
+    };
+
+    let mut index: HashMap<String, BuildFile> = HashMap::new();
+
+    let walker = WalkDir::new(config.pkgbuild_dir)
+        .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+        .into_iter()
+        .filter_entry(|entry| !is_hidden(entry) && !is_build_dir(entry));
+    for entry in walker {
+        let entry = entry.map_err(DependencyError::DirEntry)?;
+        if !entry.file_type().is_file() || entry.file_name() != "BUILD" {
+            continue;
+        }
+
+        let path = util::relative_to(config.pkgbuild_dir, entry.path());
+        match BuildFile::open(config.pkgbuild_dir, path) {
+            Ok(pkg) => match index.entry(pkg.name().to_string()) {
+                // which of the build files is the right one is anybody's guess, so just keep the
+                // first (in the order they are found) and point out the conflict
+                hash_map::Entry::Occupied(entry) => {
+                    let _ = util::display_warn(format_args!(
+                        "skipping '{}': package '{}' is already defined in '{}'",
+                        path.display(),
+                        pkg.name(),
+                        entry.get().path().display()
+                    ));
+                }
+                hash_map::Entry::Vacant(entry) => {
+                    entry.insert(pkg);
+                }
+            },
+            Err(f) => {
+                let _ = util::display_warn(format_args!("skipping '{}': {}", path.display(), f));
+            }
+        }
+    }
+
+    Ok(index)
+}
+
+// topologically sorts the given packages (packages with the same "depth" are sorted by name)
+fn sort(mut pkgs: BTreeMap<String, BuildFile>) -> Result<Vec<BuildFile>, DependencyError> {
+    let mut remaining: BTreeMap<String, Vec<String>> = pkgs.iter()
+        .map(|(name, pkg)| {
+            let deps = pkg.build_depends()
+                .into_iter()
+                .filter(|dep| pkgs.contains_key(*dep))
+                .map(|dep| dep.to_string())
+                .collect();
+            (name.clone(), deps)
+        })
+        .collect();
+
+    let mut order = vec![];
+    loop {
+        let ready: Vec<String> = remaining
+            .iter()
+            .filter(|(_, deps)| deps.is_empty())
+            .map(|(name, _)| name.clone())
+            .collect();
+        if ready.is_empty() {
+            break;
+        }
+
+        for name in &ready {
+            remaining.remove(name);
+        }
+        for deps in remaining.values_mut() {
+            deps.retain(|dep| !ready.contains(dep));
+        }
+        order.extend(ready);
+    }
+
+    if !remaining.is_empty() {
+        Err(DependencyError::Cycle(find_cycle(&remaining)))?;
+    }
+
+    Ok(order
+        .into_iter()
+        .map(|name| pkgs.remove(&name).unwrap())
+        .collect())
+}
+
+// every package left over after sorting still depends on at least one other left over package,
+// so following the dependencies from any of them must eventually lead us in a circle
+fn find_cycle(remaining: &BTreeMap<String, Vec<String>>) -> String {
+    let mut path: Vec<&str> = vec![remaining.keys().next().unwrap()];
+    loop {
+        let next = &remaining[*path.last().unwrap()][0];
+        if let Some(start) = path.iter().position(|name| name == next) {
+            let mut cycle = path.split_off(start);
+            cycle.push(next);
+            return cycle.join(" -> ");
+        }
+        path.push(next);
+    }
+}
+
+fn canonicalize(path: &Path) -> Result<PathBuf, DependencyError> {
+    path.canonicalize()
+        .map_err(|e| DependencyError::Canonicalize(path_to_string(path), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile;
+
+    fn packages(pkgs: &[(&str, &[&str])]) -> BTreeMap<String, BuildFile> {
+        pkgs.iter()
+            .map(|&(name, depends)| {
+                let fields = format!("  depends: [{}]\n  source: []\n", depends.join(", "));
+                let pkg = BuildFile::parse_test(name, &fields).unwrap();
+                (name.to_string(), pkg)
+            })
+            .collect()
+    }
+
+    fn sorted_names(pkgs: BTreeMap<String, BuildFile>) -> Vec<String> {
+        sort(pkgs)
+            .unwrap()
+            .iter()
+            .map(|pkg| pkg.name().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn sort_dependencies_first() {
+        let pkgs = packages(&[("a", &["b"]), ("b", &["c"]), ("c", &[])]);
+        assert_eq!(sorted_names(pkgs), vec!["c", "b", "a"]);
+    }
+
+    #[test]
+    fn sort_same_depth_by_name() {
+        let pkgs = packages(&[("x", &["z"]), ("z", &[]), ("y", &[]), ("w", &["y", "z"])]);
+        assert_eq!(sorted_names(pkgs), vec!["y", "z", "w", "x"]);
+    }
+
+    #[test]
+    fn sort_ignores_missing_dependencies() {
+        let pkgs = packages(&[("a", &["libc"]), ("b", &["a", "gcc"])]);
+        assert_eq!(sorted_names(pkgs), vec!["a", "b"]);
+    }
+
+    fn cycle(pkgs: BTreeMap<String, BuildFile>) -> String {
+        match sort(pkgs).unwrap_err() {
+            DependencyError::Cycle(cycle) => cycle,
+            f => panic!("{}", f),
+        }
+    }
+
+    #[test]
+    fn sort_reports_cycle() {
+        let pkgs = packages(&[("a", &["b"]), ("b", &["a"]), ("c", &[])]);
+        assert_eq!(cycle(pkgs), "a -> b -> a");
+    }
+
+    #[test]
+    fn sort_reports_self_dependency() {
+        let pkgs = packages(&[("a", &["a"])]);
+        assert_eq!(cycle(pkgs), "a -> a");
+    }
+
+    #[test]
+    fn sort_skips_bootstrap_dependencies() {
+        let mut pkgs = packages(&[("gcc", &["glibc"])]);
+        let fields = "  makedepends: [gcc]\n  bootstrap: [gcc]\n  source: []\n";
+        let glibc = BuildFile::parse_test("glibc", fields).unwrap();
+        pkgs.insert("glibc".to_string(), glibc);
+        assert_eq!(sorted_names(pkgs), vec!["glibc", "gcc"]);
+    }
+
+    #[test]
+    fn find_cycle_past_other_packages() {
+        // "a" only leads into the cycle, so it must not be reported as part of it
+        let remaining: BTreeMap<String, Vec<String>> = vec![
+            ("a", vec!["b"]),
+            ("b", vec!["c"]),
+            ("c", vec!["d", "a"]),
+            ("d", vec!["b"]),
+        ].into_iter()
+            .map(|(name, deps)| {
+                (name.to_string(), deps.into_iter().map(String::from).collect())
+            })
+            .collect();
+        assert_eq!(find_cycle(&remaining), "b -> c -> d -> b");
+    }
+
+    #[test]
+    fn resolve_keeps_first_of_duplicate_names() {
+        let pkgs = vec![
+            BuildFile::parse_test("foo", "  version: 1.0.0\n  source: []\n").unwrap(),
+            BuildFile::parse_test("bar", "  source: []\n").unwrap(),
+            BuildFile::parse_test("foo", "  version: 2.0.0\n  source: []\n").unwrap(),
+        ];
+        let pkgs = by_name(pkgs);
+        assert_eq!(pkgs.keys().collect::<Vec<_>>(), vec!["bar", "foo"]);
+        assert_eq!(pkgs["foo"].version().to_string(), "1.0.0");
+    }
+
+    // writes build files for the given packages (each in a directory of the given name) into the
+    // pkgbuild directory used by Config::test() (whose build directory has to be moved elsewhere
+    // for them to be found)
+    fn write_pkgbuild_dir(dir: &Path, pkgs: &[(&str, &str, &str)]) {
+        for &(dir_name, name, depends) in pkgs {
+            let pkg_dir = dir.join(dir_name);
+            fs::create_dir(&pkg_dir).unwrap();
+            let contents = format!(
+                "package:
+  name: {}
+  version: 1.0.0
+  description: test
+  license: []
+  skip_check: true
+  depends: [{}]
+  source: []
+",
+                name, depends
+            );
+            fs::write(pkg_dir.join("BUILD"), contents).unwrap();
+        }
+    }
+
+    #[test]
+    fn index_keeps_first_of_duplicate_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let build_dir = dir.path().join("build");
+        let mut config = Config::test(dir.path());
+        config.build_dir = &build_dir;
+        write_pkgbuild_dir(dir.path(), &[("foo", "foo", ""), ("foo-old", "foo", "")]);
+
+        let index = index_pkgbuild_dir(&config).unwrap();
+        assert_eq!(index.len(), 1);
+        assert_eq!(index["foo"].path(), Path::new("foo/BUILD"));
+    }
+
+    #[test]
+    fn resolve_adds_dependencies() {
+        let dir = tempfile::tempdir().unwrap();
+        let build_dir = dir.path().join("build");
+        let mut config = Config::test(dir.path());
+        config.build_dir = &build_dir;
+        write_pkgbuild_dir(dir.path(), &[("b", "b", "c"), ("c", "c", ""), ("d", "d", "")]);
+
+        let pkgs = vec![BuildFile::parse_test("a", "  depends: [b]\n  source: []\n").unwrap()];
+        let names: Vec<String> = resolve(&config, pkgs)
+            .unwrap()
+            .iter()
+            .map(|pkg| pkg.name().to_string())
+            .collect();
+        assert_eq!(names, vec!["c", "b", "a"]);
+    }
+
+    #[test]
+    fn resolve_reports_unknown_dependencies() {
+        let dir = tempfile::tempdir().unwrap();
+        let build_dir = dir.path().join("build");
+        let mut config = Config::test(dir.path());
+        config.build_dir = &build_dir;
+        write_pkgbuild_dir(dir.path(), &[("b", "b", "x")]);
+
+        let pkgs = vec![BuildFile::parse_test("a", "  depends: [b]\n  source: []\n").unwrap()];
+        let err = resolve(&config, pkgs).unwrap_err();
+        let errs = &err.downcast_ref::<AggregateError>().unwrap().errs;
+        assert_eq!(errs.len(), 1);
+        assert!(matches!(
+            errs[0].downcast_ref::<DependencyError>(),
+            Some(DependencyError::Unknown(ref name, ref dep)) if name == "b" && dep == "x"
+        ));
+    }
+}
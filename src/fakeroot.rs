@@ -0,0 +1,893 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::{Component, Path, PathBuf};
+
+use nix::unistd;
+
+use config::Config;
+use package::BuildFile;
+use util::path_to_string;
+
+#[derive(Debug, Fail)]
+pub enum FakerootError {
+    #[fail(display = "could not create directory '{}': {}", _0, _1)]
+    CreateDir(String, #[cause] io::Error),
+
+    #[fail(display = "could not write file '{}': {}", _0, _1)]
+    WriteFile(String, #[cause] io::Error),
+
+    #[fail(display = "could not read ownership database '{}': {}", _0, _1)]
+    ReadDatabase(String, #[cause] io::Error),
+
+    #[fail(display = "could not remove stale ownership database '{}': {}", _0, _1)]
+    RemoveDatabase(String, #[cause] io::Error),
+
+    #[fail(display = "found malformed record in ownership database '{}'", _0)]
+    InvalidRecord(String),
+
+    #[fail(display = "could not find user '{}' in '{}'", _0, _1)]
+    UnknownUser(String, String),
+
+    #[fail(display = "could not find group '{}' in '{}'", _0, _1)]
+    UnknownGroup(String, String),
+
+    #[fail(display = "invalid arguments for '{}': {}", _0, _1)]
+    InvalidArgs(String, String),
+}
+
+// name of the environment variable telling the wrapper scripts where to record commands
+const DATABASE_VAR: &str = "MKPKG_FAKEROOT_DB";
+
+// the install step cannot really change the owner of files or create device nodes without
+// running as root, so these commands are replaced by scripts that record their arguments.  each
+// record is a list of NUL-terminated fields: the number of arguments, the command, the working
+// directory, and then the arguments themselves.  NOTE: only these commands are faked.  modes
+// (including those set by chmod) are taken from the files themselves, and programs that call
+// chown(2) directly, like install -o and -g, fail with EINVAL as only root is mapped in the user
+// namespace the install step runs in
+const WRAPPERS: &[(&str, &str)] = &[
+    ("chown", RECORD_SCRIPT),
+    ("chgrp", RECORD_SCRIPT),
+    ("mknod", MKNOD_SCRIPT),
+];
+
+const RECORD_SCRIPT: &str = r#"#!/bin/sh
+printf '%s\0' "$#" "${0##*/}" "$PWD" "$@" >> "$MKPKG_FAKEROOT_DB"
+"#;
+
+// a placeholder file is created so that the device node ends up in the package
+const MKNOD_SCRIPT: &str = r#"#!/bin/sh
+printf '%s\0' "$#" "${0##*/}" "$PWD" "$@" >> "$MKPKG_FAKEROOT_DB" || exit 1
+while [ $# -gt 0 ]; do
+    case "$1" in
+        -m|--mode) shift 2 ;;
+        --) shift; break ;;
+        -*) shift ;;
+        *) break ;;
+    esac
+done
+: > "$1"
+"#;
+
+// creates the wrapper scripts (and an empty database) for the given package, returning the
+// environment variables needed for the install step to use them.  nothing needs to be faked if
+// mkpkg is running as root, in which case the files in pkgdir are packaged as they are owned
+pub fn setup(
+    config: &Config,
+    pkg: &BuildFile,
+) -> Result<Option<Vec<(String, PathBuf)>>, FakerootError> {
+    if unistd::geteuid().is_root() {
+        let database = pkg.fakeroot_dir(config).join("db");
+        match fs::remove_file(&database) {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => Err(FakerootError::RemoveDatabase(path_to_string(&database), e))?,
+        }
+        return Ok(None);
+    }
+
+    let bin_dir = pkg.fakeroot_dir(config).join("bin");
+    if !bin_dir.exists() {
+        fs::create_dir_all(&bin_dir)
+            .map_err(|e| FakerootError::CreateDir(path_to_string(&bin_dir), e))?;
+    }
+    let bin_dir = bin_dir
+        .canonicalize()
+        .map_err(|e| FakerootError::CreateDir(path_to_string(&bin_dir), e))?;
+
+    for (name, script) in WRAPPERS {
+        let path = bin_dir.join(name);
+        fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o755)
+            .open(&path)
+            .and_then(|mut file| file.write_all(script.as_bytes()))
+            .map_err(|e| FakerootError::WriteFile(path_to_string(&path), e))?;
+    }
+
+    let database = bin_dir.with_file_name("db");
+    File::create(&database)
+        .map_err(|e| FakerootError::WriteFile(path_to_string(&database), e))?;
+
+    let mut path = bin_dir.into_os_string();
+    if let Some(orig) = env::var_os("PATH") {
+        path.push(":");
+        path.push(orig);
+    }
+
+    Ok(Some(vec![
+        ("PATH".to_string(), PathBuf::from(path)),
+        (DATABASE_VAR.to_string(), database),
+    ]))
+}
+
+// a user or group as it should appear in the package
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Id {
+    pub id: u64,
+    pub name: String,
+}
+
+impl Id {
+    pub fn root() -> Self {
+        Self {
+            id: 0,
+            name: "root".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceKind {
+    Block,
+    Char,
+    Fifo,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Device {
+    pub kind: DeviceKind,
+    pub major: u32,
+    pub minor: u32,
+    pub mode: Option<u32>,
+}
+
+// the recorded attributes of a single path in pkgdir
+#[derive(Clone, Debug)]
+pub struct Attributes {
+    pub owner: Id,
+    pub group: Id,
+    pub device: Option<Device>,
+}
+
+impl Attributes {
+    // the attributes of anything not affected by the install step
+    pub fn root() -> Self {
+        Self {
+            owner: Id::root(),
+            group: Id::root(),
+            device: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Rule {
+    // relative to pkgdir
+    path: PathBuf,
+    recursive: bool,
+    owner: Option<Id>,
+    group: Option<Id>,
+    device: Option<Device>,
+}
+
+// everything recorded by the wrapper scripts during the install step of a package
+#[derive(Debug, Default)]
+pub struct Ownership {
+    // applied in order, so later rules take precedence
+    rules: Vec<Rule>,
+    // if the install step ran as root, the names of the users and groups owning files in pkgdir
+    real: Option<(HashMap<u64, String>, HashMap<u64, String>)>,
+}
+
+impl Ownership {
+    pub fn load(config: &Config, pkg: &BuildFile) -> Result<Self, FakerootError> {
+        // without a database, the install step really changed the ownership of the files
+        let database = pkg.fakeroot_dir(config).join("db");
+        if !database.exists() {
+            let names = Names::new(&pkg.root_dir(config));
+            return Ok(Self {
+                rules: vec![],
+                real: Some((names_by_id(&names.passwd)?, names_by_id(&names.group)?)),
+            });
+        }
+        let db_name = path_to_string(&database);
+
+        let contents =
+            fs::read(&database).map_err(|e| FakerootError::ReadDatabase(db_name.clone(), e))?;
+
+        let pkgdir = pkg.pkg_dir(config);
+        let pkgdir = pkgdir
+            .canonicalize()
+            .map_err(|e| FakerootError::ReadDatabase(path_to_string(&pkgdir), e))?;
+        let mut names = Names::new(&pkg.root_dir(config));
+
+        Self::parse(&contents, &db_name, &pkgdir, &mut names)
+    }
+
+    // turns the records in the database into rules, dropping those for paths outside of pkgdir
+    fn parse(
+        contents: &[u8],
+        db_name: &str,
+        pkgdir: &Path,
+        names: &mut Names,
+    ) -> Result<Self, FakerootError> {
+        let mut fields = contents
+            .split(|&byte| byte == 0)
+            .map(|field| String::from_utf8_lossy(field).into_owned());
+
+        let mut ownership = Self::default();
+        while let Some(count) = fields.next() {
+            // the last field is followed by a NUL as well
+            if count.is_empty() {
+                break;
+            }
+
+            let invalid = || FakerootError::InvalidRecord(db_name.to_string());
+            let count: usize = count.parse().map_err(|_| invalid())?;
+            let cmd = fields.next().ok_or_else(invalid)?;
+            let cwd = PathBuf::from(fields.next().ok_or_else(invalid)?);
+            let args: Vec<String> = fields.by_ref().take(count).collect();
+            if args.len() != count {
+                Err(invalid())?;
+            }
+
+            let rules = match &cmd[..] {
+                "chown" => Self::chown(&args, names, false)?,
+                "chgrp" => Self::chown(&args, names, true)?,
+                "mknod" => Self::mknod(&args)?,
+                _ => Err(invalid())?,
+            };
+            for mut rule in rules {
+                // anything outside of pkgdir won't be packaged anyway
+                if let Ok(path) = normalize(&cwd.join(&rule.path)).strip_prefix(pkgdir) {
+                    rule.path = path.to_path_buf();
+                    ownership.rules.push(rule);
+                }
+            }
+        }
+
+        Ok(ownership)
+    }
+
+    // returns the attributes the given path (relative to pkgdir) should have in the package
+    pub fn attributes(&self, path: &Path, meta: &fs::Metadata) -> Attributes {
+        match self.real {
+            Some((ref users, ref groups)) => {
+                let id = |id: u32, names: &HashMap<u64, String>| Id {
+                    id: id as u64,
+                    name: names.get(&(id as u64)).cloned().unwrap_or_default(),
+                };
+                Attributes {
+                    owner: id(meta.uid(), users),
+                    group: id(meta.gid(), groups),
+                    // real device nodes are found by the archiver itself
+                    device: None,
+                }
+            }
+            None => self.lookup(path),
+        }
+    }
+
+    // returns the recorded attributes of the given path (relative to pkgdir), which is owned by
+    // root unless something else was recorded.  NOTE: as we don't know which files existed at the time,
+    // recursive changes also apply to files created after them
+    pub fn lookup(&self, path: &Path) -> Attributes {
+        let mut attrs = Attributes::root();
+        for rule in &self.rules {
+            let matches = if rule.recursive {
+                path.starts_with(&rule.path)
+            } else {
+                path == rule.path
+            };
+            if matches {
+                if let Some(ref owner) = rule.owner {
+                    attrs.owner = owner.clone();
+                }
+                if let Some(ref group) = rule.group {
+                    attrs.group = group.clone();
+                }
+                if rule.device.is_some() {
+                    attrs.device = rule.device;
+                }
+            }
+        }
+        attrs
+    }
+
+    // handles both chown [OPTION]... OWNER[:[GROUP]] FILE... and chgrp [OPTION]... GROUP FILE...
+    fn chown(args: &[String], names: &mut Names, chgrp: bool) -> Result<Vec<Rule>, FakerootError> {
+        let cmd = if chgrp { "chgrp" } else { "chown" };
+        let (opts, operands) = split_options(args);
+
+        // anything else (such as --reference or --from) changes which files end up with which
+        // owner, so recording the command as if it wasn't there would be wrong
+        const LONG_OPTS: &[&str] = &[
+            "--recursive",
+            "--no-dereference",
+            "--verbose",
+            "--silent",
+            "--quiet",
+            "--changes",
+        ];
+        for opt in &opts {
+            let supported = if opt.starts_with("--") {
+                LONG_OPTS.contains(&opt.as_str())
+            } else {
+                opt[1..].chars().all(|c| "Rhvfc".contains(c))
+            };
+            if !supported {
+                Err(FakerootError::InvalidArgs(
+                    cmd.to_string(),
+                    format!("unsupported option '{}'", opt),
+                ))?;
+            }
+        }
+
+        let recursive = opts.iter().any(|opt| {
+            *opt == "--recursive" || (!opt.starts_with("--") && opt.contains('R'))
+        });
+
+        if operands.len() < 2 {
+            Err(FakerootError::InvalidArgs(
+                cmd.to_string(),
+                "missing operand".to_string(),
+            ))?;
+        }
+        let spec = &operands[0];
+
+        let (owner, group) = if chgrp {
+            (None, Some(names.group(spec)?))
+        } else {
+            // user names may contain dots, so the obsolete OWNER.GROUP form is only considered
+            // if there is no colon and the whole spec is not a user
+            let sep = match spec.find(':') {
+                Some(pos) => Some(pos),
+                None => match spec.find('.') {
+                    Some(pos) if !names.is_user(spec)? => Some(pos),
+                    _ => None,
+                },
+            };
+            let (user, group) = match sep {
+                Some(pos) => (&spec[..pos], Some(&spec[pos + 1..])),
+                None => (&spec[..], None),
+            };
+            let owner = if user.is_empty() {
+                None
+            } else {
+                Some(names.user(user)?)
+            };
+            let group = match group {
+                // "OWNER:" means the login group of OWNER
+                Some("") if owner.is_some() => Some(names.login_group(user)?),
+                Some("") | None => None,
+                Some(group) => Some(names.group(group)?),
+            };
+            (owner, group)
+        };
+
+        Ok(operands[1..]
+            .iter()
+            .map(|file| Rule {
+                path: PathBuf::from(file),
+                recursive,
+                owner: owner.clone(),
+                group: group.clone(),
+                device: None,
+            })
+            .collect())
+    }
+
+    // handles mknod [OPTION]... NAME TYPE [MAJOR MINOR]
+    fn mknod(args: &[String]) -> Result<Vec<Rule>, FakerootError> {
+        let invalid = |msg: &str| FakerootError::InvalidArgs("mknod".to_string(), msg.to_string());
+
+        let mut mode = None;
+        let mut operands = vec![];
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            let mode_arg = if arg == "-m" || arg == "--mode" {
+                Some(&iter.next().ok_or_else(|| invalid("missing mode"))?[..])
+            } else if let Some(mode) = arg.strip_prefix("--mode=") {
+                Some(mode)
+            } else if let Some(mode) = arg.strip_prefix("-m") {
+                Some(mode)
+            } else if arg == "--" {
+                operands.extend(iter.by_ref());
+                None
+            } else if arg.starts_with('-') && arg.len() > 1 {
+                None
+            } else {
+                operands.push(arg);
+                None
+            };
+            if let Some(mode_arg) = mode_arg {
+                mode = Some(u32::from_str_radix(mode_arg, 8)
+                    .map_err(|_| invalid("only octal modes are supported"))?);
+            }
+        }
+
+        let (kind, numbers) = match operands.get(1).map(|kind| &kind[..]) {
+            Some("b") => (DeviceKind::Block, true),
+            Some("c") | Some("u") => (DeviceKind::Char, true),
+            Some("p") => (DeviceKind::Fifo, false),
+            _ => Err(invalid("missing or unknown device type"))?,
+        };
+        let (major, minor) = if numbers {
+            let parse = |num: Option<&&String>| {
+                num.and_then(|num| parse_number(num))
+                    .ok_or_else(|| invalid("missing or invalid device number"))
+            };
+            (parse(operands.get(2))?, parse(operands.get(3))?)
+        } else {
+            (0, 0)
+        };
+
+        Ok(vec![Rule {
+            path: PathBuf::from(operands[0]),
+            recursive: false,
+            owner: None,
+            group: None,
+            device: Some(Device {
+                kind,
+                major,
+                minor,
+                mode,
+            }),
+        }])
+    }
+}
+
+// resolves user and group names using the passwd and group files of the root the build runs in
+struct Names {
+    passwd: PathBuf,
+    group: PathBuf,
+    // maps each user to its uid and login group and each group to its gid
+    users: Option<HashMap<String, (u64, u64)>>,
+    groups: Option<HashMap<String, u64>>,
+}
+
+impl Names {
+    fn new(root: &Path) -> Self {
+        let etc = root.join("etc");
+        Self {
+            passwd: etc.join("passwd"),
+            group: etc.join("group"),
+            users: None,
+            groups: None,
+        }
+    }
+
+    fn user(&mut self, name: &str) -> Result<Id, FakerootError> {
+        if let Some(id) = parse_number(name) {
+            let name = self.users()?
+                .iter()
+                .find(|(_, &(uid, _))| uid == id as u64)
+                .map(|(name, _)| name.clone())
+                .unwrap_or_default();
+            return Ok(Id {
+                id: id as u64,
+                name,
+            });
+        }
+        match self.users()?.get(name) {
+            Some(&(uid, _)) => Ok(Id {
+                id: uid,
+                name: name.to_string(),
+            }),
+            None => Err(FakerootError::UnknownUser(
+                name.to_string(),
+                path_to_string(&self.passwd),
+            )),
+        }
+    }
+
+    fn is_user(&mut self, name: &str) -> Result<bool, FakerootError> {
+        Ok(self.users()?.contains_key(name))
+    }
+
+    fn login_group(&mut self, user: &str) -> Result<Id, FakerootError> {
+        let gid = match self.users()?.get(user) {
+            Some(&(_, gid)) => gid,
+            None => Err(FakerootError::UnknownUser(
+                user.to_string(),
+                path_to_string(&self.passwd),
+            ))?,
+        };
+        self.group(&gid.to_string())
+    }
+
+    fn group(&mut self, name: &str) -> Result<Id, FakerootError> {
+        if let Some(id) = parse_number(name) {
+            let name = self.groups()?
+                .iter()
+                .find(|(_, &gid)| gid == id as u64)
+                .map(|(name, _)| name.clone())
+                .unwrap_or_default();
+            return Ok(Id {
+                id: id as u64,
+                name,
+            });
+        }
+        match self.groups()?.get(name) {
+            Some(&gid) => Ok(Id {
+                id: gid,
+                name: name.to_string(),
+            }),
+            None => Err(FakerootError::UnknownGroup(
+                name.to_string(),
+                path_to_string(&self.group),
+            )),
+        }
+    }
+
+    fn users(&mut self) -> Result<&HashMap<String, (u64, u64)>, FakerootError> {
+        if self.users.is_none() {
+            let mut users = HashMap::new();
+            for fields in read_entries(&self.passwd)? {
+                // name:password:uid:gid:...
+                let uid = fields.get(2).and_then(|uid| parse_number(uid));
+                let gid = fields.get(3).and_then(|gid| parse_number(gid));
+                if let (Some(uid), Some(gid)) = (uid, gid) {
+                    users
+                        .entry(fields[0].clone())
+                        .or_insert((uid as u64, gid as u64));
+                }
+            }
+            self.users = Some(users);
+        }
+        Ok(self.users.as_ref().unwrap())
+    }
+
+    fn groups(&mut self) -> Result<&HashMap<String, u64>, FakerootError> {
+        if self.groups.is_none() {
+            let mut groups = HashMap::new();
+            for fields in read_entries(&self.group)? {
+                // name:password:gid:members
+                if let Some(gid) = fields.get(2).and_then(|gid| parse_number(gid)) {
+                    groups.entry(fields[0].clone()).or_insert(gid as u64);
+                }
+            }
+            self.groups = Some(groups);
+        }
+        Ok(self.groups.as_ref().unwrap())
+    }
+}
+
+// reads the colon-separated entries of a file like /etc/passwd (which may not exist)
+fn read_entries(path: &Path) -> Result<Vec<Vec<String>>, FakerootError> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => Err(FakerootError::ReadDatabase(path_to_string(path), e))?,
+    };
+
+    let mut entries = vec![];
+    for line in BufReader::new(file).lines() {
+        let line = line.map_err(|e| FakerootError::ReadDatabase(path_to_string(path), e))?;
+        if !line.starts_with('#') && !line.is_empty() {
+            entries.push(line.split(':').map(|field| field.to_string()).collect());
+        }
+    }
+    Ok(entries)
+}
+
+// maps the ids in a file like /etc/passwd or /etc/group (both of which have the id in their third
+// field) to the first name listed for each
+fn names_by_id(path: &Path) -> Result<HashMap<u64, String>, FakerootError> {
+    let mut names = HashMap::new();
+    for fields in read_entries(path)? {
+        if let Some(id) = fields.get(2).and_then(|id| parse_number(id)) {
+            names.entry(id as u64).or_insert_with(|| fields[0].clone());
+        }
+    }
+    Ok(names)
+}
+
+// splits the arguments of a command like chown into options and operands
+fn split_options(args: &[String]) -> (Vec<&String>, Vec<&String>) {
+    let mut opts = vec![];
+    let mut operands = vec![];
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if arg == "--" {
+            operands.extend(iter.by_ref());
+        } else if arg.starts_with('-') && arg.len() > 1 {
+            opts.push(arg);
+        } else {
+            operands.push(arg);
+        }
+    }
+    (opts, operands)
+}
+
+fn parse_number(num: &str) -> Option<u32> {
+    num.parse().ok()
+}
+
+// removes "." and ".." from the path without touching the filesystem
+fn normalize(path: &Path) -> PathBuf {
+    let mut result = PathBuf::new();
+    for comp in path.components() {
+        match comp {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                result.pop();
+            }
+            comp => result.push(comp.as_os_str()),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names() -> Names {
+        let users = [
+            ("root", 0, 0),
+            ("bin", 1, 1),
+            ("user", 1000, 100),
+            ("first.last", 1001, 100),
+        ];
+        let groups = [("root", 0), ("bin", 1), ("wheel", 10), ("users", 100)];
+        Names {
+            passwd: PathBuf::from("/etc/passwd"),
+            group: PathBuf::from("/etc/group"),
+            users: Some(
+                users
+                    .iter()
+                    .map(|&(name, uid, gid)| (name.to_string(), (uid, gid)))
+                    .collect(),
+            ),
+            groups: Some(
+                groups
+                    .iter()
+                    .map(|&(name, gid)| (name.to_string(), gid))
+                    .collect(),
+            ),
+        }
+    }
+
+    // a record as written by the wrapper scripts
+    fn record(cwd: &str, cmd: &str, args: &[&str]) -> Vec<u8> {
+        let mut fields = vec![args.len().to_string(), cmd.to_string(), cwd.to_string()];
+        fields.extend(args.iter().map(|arg| arg.to_string()));
+
+        let mut record = vec![];
+        for field in fields {
+            record.extend(field.as_bytes());
+            record.push(0);
+        }
+        record
+    }
+
+    fn parse(records: &[Vec<u8>]) -> Result<Ownership, FakerootError> {
+        Ownership::parse(&records.concat(), "db", Path::new("/pkg"), &mut names())
+    }
+
+    fn owners(ownership: &Ownership, path: &str) -> (String, String) {
+        let attrs = ownership.lookup(Path::new(path));
+        (attrs.owner.name, attrs.group.name)
+    }
+
+    fn id(id: u64, name: &str) -> Id {
+        Id {
+            id,
+            name: name.to_string(),
+        }
+    }
+
+    #[test]
+    fn chown_owner_and_group() {
+        let ownership = parse(&[
+            record("/pkg", "chown", &["bin:users", "a"]),
+            record("/pkg", "chown", &["user:", "b"]),
+            record("/pkg", "chown", &[":wheel", "c"]),
+            record("/pkg", "chown", &["bin", "d"]),
+        ]).unwrap();
+
+        let attrs = ownership.lookup(Path::new("a"));
+        assert_eq!((attrs.owner, attrs.group), (id(1, "bin"), id(100, "users")));
+        // "OWNER:" means the login group of OWNER
+        assert_eq!(owners(&ownership, "b"), ("user".into(), "users".into()));
+        assert_eq!(owners(&ownership, "c"), ("root".into(), "wheel".into()));
+        assert_eq!(owners(&ownership, "d"), ("bin".into(), "root".into()));
+    }
+
+    #[test]
+    fn chown_dotted_specs() {
+        let ownership = parse(&[
+            record("/pkg", "chown", &["bin.users", "a"]),
+            record("/pkg", "chown", &["first.last", "b"]),
+            record("/pkg", "chown", &["first.last:bin", "c"]),
+        ]).unwrap();
+
+        // a dot only separates the owner from the group if the whole spec isn't a user
+        assert_eq!(owners(&ownership, "a"), ("bin".into(), "users".into()));
+        assert_eq!(owners(&ownership, "b"), ("first.last".into(), "root".into()));
+        assert_eq!(owners(&ownership, "c"), ("first.last".into(), "bin".into()));
+    }
+
+    #[test]
+    fn chown_numeric_ids() {
+        let ownership = parse(&[
+            record("/pkg", "chown", &["1:10", "a"]),
+            record("/pkg", "chown", &["4242:4242", "b"]),
+        ]).unwrap();
+
+        let attrs = ownership.lookup(Path::new("a"));
+        assert_eq!((attrs.owner, attrs.group), (id(1, "bin"), id(10, "wheel")));
+        let attrs = ownership.lookup(Path::new("b"));
+        assert_eq!((attrs.owner, attrs.group), (id(4242, ""), id(4242, "")));
+    }
+
+    #[test]
+    fn chgrp_recursive() {
+        let ownership = parse(&[
+            record("/pkg", "chgrp", &["-Rv", "wheel", "usr"]),
+            record("/pkg", "chown", &["--", "bin", "usr/bin/su"]),
+        ]).unwrap();
+
+        assert_eq!(owners(&ownership, "usr"), ("root".into(), "wheel".into()));
+        assert_eq!(owners(&ownership, "usr/lib/libc.so"), ("root".into(), "wheel".into()));
+        assert_eq!(owners(&ownership, "usr/bin/su"), ("bin".into(), "wheel".into()));
+        assert_eq!(owners(&ownership, "usrx"), ("root".into(), "root".into()));
+    }
+
+    #[test]
+    fn later_rules_take_precedence() {
+        let ownership = parse(&[
+            record("/pkg", "chown", &["bin:bin", "a"]),
+            record("/pkg", "chown", &["-R", "user", "."]),
+        ]).unwrap();
+
+        assert_eq!(owners(&ownership, "a"), ("user".into(), "bin".into()));
+    }
+
+    #[test]
+    fn paths_relative_to_cwd() {
+        let ownership = parse(&[
+            record("/pkg/usr", "chown", &["bin", "../etc/./passwd", "/tmp/x", "../../pkg2"]),
+        ]).unwrap();
+
+        assert_eq!(ownership.rules.len(), 1);
+        assert_eq!(owners(&ownership, "etc/passwd"), ("bin".into(), "root".into()));
+    }
+
+    #[test]
+    fn real_ownership_without_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config::test(dir.path());
+        let root = dir.path().join("root");
+        config.toolchain_root = &root;
+        let pkg = BuildFile::parse_test("foo", "  source: []\n").unwrap();
+
+        let path = dir.path().join("a");
+        fs::write(&path, "a\n").unwrap();
+        let meta = fs::symlink_metadata(&path).unwrap();
+        let passwd = format!(
+            "builder:x:{}:{}::/:/bin/sh\nother:x:{0}:{1}::/:/bin/sh\n",
+            meta.uid(),
+            meta.gid()
+        );
+        fs::create_dir_all(root.join("etc")).unwrap();
+        fs::write(root.join("etc/passwd"), passwd).unwrap();
+
+        // without a database the install step ran as root, so the files themselves are used
+        let ownership = Ownership::load(&config, &pkg).unwrap();
+        let attrs = ownership.attributes(Path::new("a"), &meta);
+        assert_eq!(
+            (attrs.owner, attrs.group),
+            (id(meta.uid() as u64, "builder"), id(meta.gid() as u64, ""))
+        );
+    }
+
+    #[test]
+    fn chown_errors() {
+        let res = parse(&[record("/pkg", "chown", &["nobody:bin", "a"])]);
+        assert!(matches!(res, Err(FakerootError::UnknownUser(ref name, _)) if name == "nobody"));
+        let res = parse(&[record("/pkg", "chgrp", &["nogroup", "a"])]);
+        assert!(matches!(res, Err(FakerootError::UnknownGroup(ref name, _)) if name == "nogroup"));
+        let res = parse(&[record("/pkg", "chown", &["-R", "bin"])]);
+        assert!(matches!(res, Err(FakerootError::InvalidArgs(ref cmd, _)) if cmd == "chown"));
+    }
+
+    #[test]
+    fn chown_reference_unsupported() {
+        let res = parse(&[record("/pkg", "chown", &["--reference=b", "a"])]);
+        assert!(matches!(res, Err(FakerootError::InvalidArgs(ref cmd, _)) if cmd == "chown"));
+        let res = parse(&[record("/pkg", "chgrp", &["--reference", "b", "a"])]);
+        assert!(matches!(res, Err(FakerootError::InvalidArgs(ref cmd, _)) if cmd == "chgrp"));
+    }
+
+    #[test]
+    fn chown_from_unsupported() {
+        let res = parse(&[record("/pkg", "chown", &["--from=root:root", "bin", "a"])]);
+        assert!(matches!(res, Err(FakerootError::InvalidArgs(ref cmd, _)) if cmd == "chown"));
+    }
+
+    #[test]
+    fn mknod_devices() {
+        let ownership = parse(&[
+            record("/pkg/dev", "mknod", &["-m", "600", "null", "c", "1", "3"]),
+            record("/pkg/dev", "mknod", &["-m0660", "sda", "b", "8", "0"]),
+            record("/pkg/dev", "mknod", &["--mode=644", "--", "initctl", "p"]),
+        ]).unwrap();
+
+        let device = |path| ownership.lookup(Path::new(path)).device;
+        assert_eq!(
+            device("dev/null"),
+            Some(Device {
+                kind: DeviceKind::Char,
+                major: 1,
+                minor: 3,
+                mode: Some(0o600),
+            })
+        );
+        assert_eq!(
+            device("dev/sda"),
+            Some(Device {
+                kind: DeviceKind::Block,
+                major: 8,
+                minor: 0,
+                mode: Some(0o660),
+            })
+        );
+        assert_eq!(
+            device("dev/initctl"),
+            Some(Device {
+                kind: DeviceKind::Fifo,
+                major: 0,
+                minor: 0,
+                mode: Some(0o644),
+            })
+        );
+        assert_eq!(device("dev"), None);
+    }
+
+    #[test]
+    fn mknod_errors() {
+        for args in &[
+            &["null", "x", "1", "3"][..],
+            &["null", "c", "1"],
+            &["null", "c", "one", "3"],
+            &["-m", "u+rw", "null", "c", "1", "3"],
+            &["null", "c", "1", "3", "-m"],
+        ] {
+            let res = parse(&[record("/pkg", "mknod", args)]);
+            assert!(matches!(res, Err(FakerootError::InvalidArgs(ref cmd, _)) if cmd == "mknod"));
+        }
+    }
+
+    #[test]
+    fn malformed_records() {
+        let mut truncated = record("/pkg", "chown", &["bin", "a"]);
+        truncated.truncate(truncated.len() - "bin\0a\0".len());
+        for contents in &[
+            b"x\0chown\0/pkg\0".to_vec(),
+            b"1\0chown\0".to_vec(),
+            truncated,
+            record("/pkg", "rm", &["-rf", "a"]),
+        ] {
+            let res = Ownership::parse(contents, "db", Path::new("/pkg"), &mut names());
+            assert!(matches!(res, Err(FakerootError::InvalidRecord(ref db)) if db == "db"));
+        }
+    }
+}
@@ -14,35 +14,57 @@ extern crate url;
 #[macro_use]
 extern crate failure_derive;
 extern crate indicatif;
-extern crate tempfile;
 extern crate term_size;
 extern crate unicode_xid;
 extern crate walkdir;
+#[cfg(test)]
+extern crate tempfile;
 
 // downloading source code/patches
 extern crate git2;
 extern crate reqwest;
+extern crate hyper;
 
-// compression of downloaded files
+// compression of downloaded files and built packages
 extern crate bzip2;
 extern crate flate2;
+extern crate sevenz_rust;
 extern crate tar;
 extern crate xz2;
+extern crate zip;
+extern crate zstd;
+
+// isolation of build commands
+extern crate libc;
+extern crate nix;
+
+// verification of downloaded files
+extern crate blake2;
+extern crate sha2;
 
 use clap::{AppSettings, Arg, ArgMatches, SubCommand};
+use std::collections::HashMap;
+use std::env;
 use std::ffi::OsStr;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process;
 use std::u32;
 
+use archive::Compression;
 use config::{Action, Config};
 
 mod archive;
 mod builder;
+mod checksum;
 mod config;
+mod dependency;
+mod fakeroot;
+mod metadata;
 mod network;
 mod package;
 mod progress;
+mod sandbox;
+mod signature;
 #[allow(dead_code)]
 mod util;
 
@@ -59,6 +81,10 @@ fn main() {
                             .takes_value(true)
                             .default_value_os(OsStr::new("build"))
                             .help("Set the directory in which to download and build packages"))
+                    .arg(Arg::with_name("cache-dir")
+                            .long("cache-dir")
+                            .takes_value(true)
+                            .help("Set the directory in which to cache downloaded sources (defaults to cache in the build directory)"))
                     .arg(Arg::with_name("accept")
                             .long("accept")
                             .takes_value(true)
@@ -73,12 +99,51 @@ fn main() {
                     .arg(Arg::with_name("fail-fast")
                             .long("fail-fast")
                             .help("Stop as soon as an error occurs"))
+                    .arg(Arg::with_name("mirrors")
+                            .long("mirrors")
+                            .takes_value(true)
+                            .help("Set the file listing the mirrors for URLs like gnu://path (defaults to mirrors.yaml in the pkgbuild directory)"))
+                    .arg(Arg::with_name("offline")
+                            .long("offline")
+                            .help("Use only sources that have already been downloaded rather than connecting to the network"))
+                    .arg(Arg::with_name("allow-network")
+                            .long("allow-network")
+                            .help("Let build commands access the network even if their package does not set options: [network]"))
+                    .arg(Arg::with_name("sandbox")
+                            .long("sandbox")
+                            .requires("toolchain-root")
+                            .help("Run build commands in isolated user, mount, PID, and network namespaces"))
+                    .arg(Arg::with_name("toolchain-root")
+                            .long("toolchain-root")
+                            .takes_value(true)
+                            .requires("sandbox")
+                            .help("Set the directory mounted read-only as the root of the sandbox"))
+                    .arg(Arg::with_name("sysroot")
+                            .long("sysroot")
+                            .requires("sandbox")
+                            .help("Use the built dependencies of each package as the root of its sandbox"))
+                    .arg(Arg::with_name("keyring-dir")
+                            .long("keyring-dir")
+                            .takes_value(true)
+                            .help("Set the directory of public keys used to verify signed sources (defaults to keys in the pkgbuild directory)"))
+                    .arg(Arg::with_name("compression")
+                            .long("compression")
+                            .takes_value(true)
+                            .possible_values(Compression::NAMES)
+                            .default_value("xz")
+                            .help("Set the format used to compress packages that don't specify one"))
                     .arg(Arg::with_name("parallel-download")
                             .long("parallel-download")
                             .short("d")
                             .takes_value(true)
                             .validator(is_u32)
                             .help("Set the number of downloads to occur in parallel"))
+                    .arg(Arg::with_name("retries")
+                            .long("retries")
+                            .takes_value(true)
+                            .validator(is_u32)
+                            .default_value("3")
+                            .help("Set the number of times to retry failed downloads (waiting longer after each attempt)"))
                     .arg(Arg::with_name("parallel-build")
                             .long("parallel-build")
                             .short("b")
@@ -100,6 +165,11 @@ fn main() {
                                     .index(1)
                                     .required(true)
                                     .multiple(true)))
+                    .subcommand(SubCommand::with_name("updsums")
+                            .arg(Arg::with_name("PKGBUILD")
+                                    .index(1)
+                                    .required(true)
+                                    .multiple(true)))
                     // important to note that we require a package argument (unlike the shell
                     // version which just installed all packages), so we need some sort of shell
                     // script to just call mkpkg with all the packages as arguments to build
@@ -108,6 +178,55 @@ fn main() {
 
     let pkgdir = Path::new(matches.value_of_os("pkgbuild-dir").unwrap());
     let builddir = Path::new(matches.value_of_os("build-dir").unwrap());
+    // the sandbox requires a toolchain root to be given (as exposing the host's root would defeat
+    // its purpose), so this only matters for the unsandboxed builds that run in / anyway.  a
+    // default value would count as the argument being present, which would always require
+    // --sandbox
+    let toolchain_root = matches
+        .value_of_os("toolchain-root")
+        .map(Path::new)
+        .unwrap_or_else(|| Path::new("/"));
+
+    let cache_dir = matches
+        .value_of_os("cache-dir")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| builddir.join("cache"));
+    let keyring_dir = matches
+        .value_of_os("keyring-dir")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| pkgdir.join("keys"));
+
+    // the default mirrors file is optional, but one given on the command line is not
+    let mirrors = match matches.value_of_os("mirrors") {
+        Some(path) => config::load_mirrors(Path::new(path)),
+        None => {
+            let path = pkgdir.join("mirrors.yaml");
+            if path.exists() {
+                config::load_mirrors(&path)
+            } else {
+                Ok(HashMap::new())
+            }
+        }
+    };
+    let mirrors = match mirrors {
+        Ok(mirrors) => mirrors,
+        Err(f) => {
+            let _ = util::display_err(format_args!("{}", f));
+            process::exit(1);
+        }
+    };
+
+    // see https://reproducible-builds.org/specs/source-date-epoch/
+    let source_date_epoch = match env::var("SOURCE_DATE_EPOCH") {
+        Ok(val) => match val.parse::<u64>() {
+            Ok(epoch) => Some(epoch),
+            Err(f) => {
+                let _ = util::display_err(format_args!("invalid SOURCE_DATE_EPOCH: {}", f));
+                process::exit(1);
+            }
+        },
+        Err(_) => None,
+    };
 
     let licenses = matches
         .values_of_os("accept")
@@ -121,8 +240,19 @@ fn main() {
         verbose: matches.is_present("verbose"),
         clobber: matches.is_present("clobber"),
         fail_fast: matches.is_present("fail-fast"),
+        offline: matches.is_present("offline"),
+        allow_network: matches.is_present("allow-network"),
+        sandbox: matches.is_present("sandbox"),
+        toolchain_root,
+        sysroot: matches.is_present("sysroot"),
+        source_date_epoch,
+        cache_dir: &cache_dir,
+        keyring_dir: &keyring_dir,
+        mirrors,
+        compression: value_t_or_exit!(matches, "compression", Compression),
         parallel_download: convert_u32(matches.value_of("parallel-download")),
         parallel_build: convert_u32(matches.value_of("parallel-build")),
+        retries: value_t_or_exit!(matches, "retries", u32),
         action: determine_action(&matches),
     };
 
@@ -143,6 +273,9 @@ fn determine_action<'a>(matches: &'a ArgMatches<'a>) -> Action<'a> {
         ("describe", Some(matches)) => Action::Describe {
             pkgs: matches.values_of_os("PKGBUILD").unwrap(),
         },
+        ("updsums", Some(matches)) => Action::UpdateSums {
+            pkgs: matches.values_of_os("PKGBUILD").unwrap(),
+        },
         _ => unreachable!(),
     }
 }
@@ -0,0 +1,333 @@
+use nix::unistd;
+use walkdir::Error as WalkError;
+use walkdir::WalkDir;
+
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use checksum::ChecksumKind;
+use config::Config;
+use fakeroot::{DeviceKind, Ownership};
+use package::BuildFile;
+use util::{self, nix_to_io, path_to_string};
+
+#[derive(Debug, Fail)]
+pub enum MetadataError {
+    #[fail(display = "could not determine host name: {}", _0)]
+    HostName(#[cause] io::Error),
+
+    #[fail(display = "found invalid directory entry while measuring package: {}", _0)]
+    DirEntry(#[cause] WalkError),
+
+    #[fail(display = "could not read symlink '{}': {}", _0, _1)]
+    ReadLink(String, #[cause] io::Error),
+
+    #[fail(display = "could not compute checksum of '{}': {}", _0, _1)]
+    Hash(String, #[cause] io::Error),
+}
+
+// name of the file at the root of each package archive describing the package
+pub const PKGINFO: &str = ".PKGINFO";
+
+// name of the file at the root of each package archive listing the files in the package
+pub const MTREE: &str = ".MTREE";
+
+// generates the contents of .PKGINFO for the given package, which consists of "key = value"
+// lines (keys with multiple values are simply repeated) in the same format used by pacman
+pub fn pkginfo(config: &Config, pkg: &BuildFile) -> Result<String, MetadataError> {
+    // the host name would make the package differ depending on which machine built it
+    let buildhost = match config.source_date_epoch {
+        Some(_) => None,
+        None => Some(host_name()?),
+    };
+    let size = installed_size(config, pkg)?;
+    Ok(format_pkginfo(pkg, build_date(config), buildhost.as_ref().map(|host| &host[..]), size))
+}
+
+// the values that don't come from the build file are measured or looked up by pkginfo()
+fn format_pkginfo(pkg: &BuildFile, builddate: u64, buildhost: Option<&str>, size: u64) -> String {
+    let mut info = String::new();
+
+    let mut add = |key: &str, value: &str| {
+        // newlines would break the format (and are unlikely to be intentional anyway)
+        let value = value.replace('\n', " ");
+        let _ = writeln!(info, "{} = {}", key, value);
+    };
+
+    add("pkgname", pkg.name());
+    add("pkgver", &pkg.version().to_string());
+    add("pkgdesc", pkg.description());
+    add("builddate", &builddate.to_string());
+    if let Some(buildhost) = buildhost {
+        add("buildhost", buildhost);
+    }
+    add("size", &size.to_string());
+    for license in pkg.license() {
+        add("license", license);
+    }
+    for (key, deps) in &[
+        ("depend", pkg.depends()),
+        ("makedepend", pkg.makedepends()),
+        ("checkdepend", pkg.checkdepends()),
+    ] {
+        for dep in deps.iter() {
+            add(key, dep);
+        }
+    }
+
+    format!("# generated by mkpkg {}\n{}", crate_version!(), info)
+}
+
+// seconds since the epoch at which the package is considered to have been built (which is fixed
+// by SOURCE_DATE_EPOCH for reproducible builds)
+pub fn build_date(config: &Config) -> u64 {
+    config.source_date_epoch.unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs())
+            .unwrap_or(0)
+    })
+}
+
+fn host_name() -> Result<String, MetadataError> {
+    let mut buf = [0; 256];
+    let name = unistd::gethostname(&mut buf)
+        .map_err(|e| MetadataError::HostName(nix_to_io(e)))?;
+    Ok(name.to_string_lossy().into_owned())
+}
+
+// the total size (in bytes) of the files the package will install
+fn installed_size(config: &Config, pkg: &BuildFile) -> Result<u64, MetadataError> {
+    let mut size = 0;
+    for entry in WalkDir::new(pkg.pkg_dir(config)) {
+        let entry = entry.map_err(MetadataError::DirEntry)?;
+        if entry.file_type().is_file() {
+            size += entry.metadata().map_err(MetadataError::DirEntry)?.len();
+        }
+    }
+    Ok(size)
+}
+
+// generates the contents of .MTREE for the package in pkgdir, which lists every path in the
+// package (sorted, so the output only depends on the files themselves) in the format used by
+// mtree(5)
+pub fn mtree(pkgdir: &Path, ownership: &Ownership) -> Result<String, MetadataError> {
+    let mut manifest = String::from("#mtree\n");
+    let walker = WalkDir::new(pkgdir).sort_by(|a, b| a.file_name().cmp(b.file_name()));
+    for entry in walker {
+        let entry = entry.map_err(MetadataError::DirEntry)?;
+        let meta = entry.metadata().map_err(MetadataError::DirEntry)?;
+        let path = entry.path();
+        if meta.file_type().is_socket() {
+            // sockets are not archived
+            continue;
+        }
+
+        let rel = util::relative_to(pkgdir, path);
+        let name = if rel.as_os_str().is_empty() {
+            PathBuf::from(".")
+        } else {
+            Path::new(".").join(rel)
+        };
+        // the ownership (and device nodes) recorded during the install step override whatever
+        // is actually in pkgdir
+        let attrs = ownership.attributes(rel, &meta);
+        let (kind, mode) = match attrs.device {
+            Some(device) => {
+                let kind = match device.kind {
+                    DeviceKind::Block => "block",
+                    DeviceKind::Char => "char",
+                    DeviceKind::Fifo => "fifo",
+                };
+                (kind, device.mode.unwrap_or(meta.mode()))
+            }
+            None => (file_type(&meta.file_type()), meta.mode()),
+        };
+        let _ = write!(
+            manifest,
+            "{} type={} mode={:o} uid={} gid={}",
+            escape(&name),
+            kind,
+            mode & 0o7777,
+            attrs.owner.id,
+            attrs.group.id
+        );
+        if !attrs.owner.name.is_empty() {
+            let _ = write!(manifest, " uname={}", escape(Path::new(&attrs.owner.name)));
+        }
+        if !attrs.group.name.is_empty() {
+            let _ = write!(manifest, " gname={}", escape(Path::new(&attrs.group.name)));
+        }
+
+        if let Some(device) = attrs.device {
+            if device.kind != DeviceKind::Fifo {
+                let _ = write!(manifest, " device=native,{},{}", device.major, device.minor);
+            }
+        } else if meta.file_type().is_file() {
+            let digest = ChecksumKind::Sha256
+                .hash_file(path)
+                .map_err(|e| MetadataError::Hash(path_to_string(path), e))?;
+            let _ = write!(manifest, " size={} sha256digest={}", meta.len(), digest);
+        } else if meta.file_type().is_symlink() {
+            let target = fs::read_link(path)
+                .map_err(|e| MetadataError::ReadLink(path_to_string(path), e))?;
+            let _ = write!(manifest, " link={}", escape(&target));
+        }
+        manifest.push('\n');
+    }
+
+    Ok(manifest)
+}
+
+fn file_type(file_type: &fs::FileType) -> &'static str {
+    if file_type.is_dir() {
+        "dir"
+    } else if file_type.is_symlink() {
+        "link"
+    } else if file_type.is_block_device() {
+        "block"
+    } else if file_type.is_char_device() {
+        "char"
+    } else if file_type.is_fifo() {
+        "fifo"
+    } else if file_type.is_socket() {
+        "socket"
+    } else {
+        "file"
+    }
+}
+
+// whitespace, backslashes, and anything that isn't printable ASCII are written as octal escapes
+// so that every entry stays on one line and can be split on spaces
+fn escape(path: &Path) -> String {
+    let mut result = String::new();
+    for &byte in path.as_os_str().as_bytes() {
+        if byte.is_ascii_graphic() && byte != b'\\' {
+            result.push(byte as char);
+        } else {
+            let _ = write!(result, "\\{:03o}", byte);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::{symlink, PermissionsExt};
+    use std::os::unix::net::UnixListener;
+    use tempfile;
+
+    // the .PKGINFO lines after the comment saying which version of mkpkg generated it
+    fn pkginfo_lines(pkg: &BuildFile, buildhost: Option<&str>) -> Vec<String> {
+        let info = format_pkginfo(pkg, 1234567890, buildhost, 4096);
+        info.lines().skip(1).map(String::from).collect()
+    }
+
+    #[test]
+    fn pkginfo_fields() {
+        let fields = "  description: the foo tool
+  license: [MIT, Apache-2.0]
+  depends: [glibc, zlib]
+  makedepends: [gcc]
+  source: []
+";
+        let pkg = BuildFile::parse_test("foo", fields).unwrap();
+        assert_eq!(
+            pkginfo_lines(&pkg, Some("builder")),
+            vec![
+                "pkgname = foo",
+                "pkgver = 1.2.3",
+                "pkgdesc = the foo tool",
+                "builddate = 1234567890",
+                "buildhost = builder",
+                "size = 4096",
+                "license = MIT",
+                "license = Apache-2.0",
+                "depend = glibc",
+                "depend = zlib",
+                "makedepend = gcc",
+            ]
+        );
+    }
+
+    #[test]
+    fn pkginfo_flattens_newlines() {
+        let fields = "  description: \"a tool\\nthat does foo\\n\"
+  license: [\"MIT\\nor Apache-2.0\"]
+  source: []
+";
+        let pkg = BuildFile::parse_test("foo", fields).unwrap();
+        let lines = pkginfo_lines(&pkg, Some("build\nhost"));
+        assert_eq!(lines.len(), 7);
+        assert_eq!(lines[2], "pkgdesc = a tool that does foo ");
+        assert_eq!(lines[4], "buildhost = build host");
+        assert_eq!(lines[6], "license = MIT or Apache-2.0");
+    }
+
+    #[test]
+    fn pkginfo_reproducible_without_buildhost() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config::test(dir.path());
+        let pkg = BuildFile::parse_test("foo", "  source: []\n").unwrap();
+        fs::create_dir_all(pkg.pkg_dir(&config)).unwrap();
+        fs::write(pkg.pkg_dir(&config).join("file"), b"1234").unwrap();
+
+        let info = pkginfo(&config, &pkg).unwrap();
+        assert!(info.contains("\nbuildhost = "));
+
+        config.source_date_epoch = Some(1234567890);
+        let info = pkginfo(&config, &pkg).unwrap();
+        assert!(!info.contains("buildhost"));
+        assert!(info.contains("\nbuilddate = 1234567890\n"));
+        assert!(info.contains("\nsize = 4\n"));
+    }
+
+    #[test]
+    fn escape_special_bytes() {
+        assert_eq!(escape(Path::new("./usr/bin/foo")), "./usr/bin/foo");
+        assert_eq!(escape(Path::new("./my file")), "./my\\040file");
+        assert_eq!(escape(Path::new("./back\\slash")), "./back\\134slash");
+        assert_eq!(escape(Path::new("./tab\tnew\nline")), "./tab\\011new\\012line");
+        assert_eq!(escape(Path::new("./caf\u{e9}")), "./caf\\303\\251");
+    }
+
+    #[test]
+    fn mtree_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config::test(dir.path());
+        let pkg = BuildFile::parse_test("foo", "  source: []\n").unwrap();
+        let pkgdir = pkg.pkg_dir(&config);
+
+        fs::create_dir_all(pkgdir.join("usr")).unwrap();
+        fs::write(pkgdir.join("usr/b file"), b"hello\n").unwrap();
+        symlink("b file", pkgdir.join("usr/a")).unwrap();
+        fs::write(pkgdir.join("null"), b"").unwrap();
+        let _listener = UnixListener::bind(pkgdir.join("sock")).unwrap();
+        for (path, mode) in &[("", 0o755), ("usr", 0o750), ("usr/b file", 0o644)] {
+            fs::set_permissions(pkgdir.join(path), fs::Permissions::from_mode(*mode)).unwrap();
+        }
+
+        // a device node that was recorded (but only created as a regular file) by the install step
+        let cwd = pkgdir.canonicalize().unwrap();
+        let record = ["6", "mknod", cwd.to_str().unwrap(), "-m", "666", "null", "c", "1", "3", ""];
+        fs::create_dir_all(pkg.fakeroot_dir(&config)).unwrap();
+        fs::write(pkg.fakeroot_dir(&config).join("db"), record.join("\0")).unwrap();
+        let ownership = Ownership::load(&config, &pkg).unwrap();
+
+        let expected = "#mtree
+. type=dir mode=755 uid=0 gid=0 uname=root gname=root
+./null type=char mode=666 uid=0 gid=0 uname=root gname=root device=native,1,3
+./usr type=dir mode=750 uid=0 gid=0 uname=root gname=root
+./usr/a type=link mode=777 uid=0 gid=0 uname=root gname=root link=b\\040file
+./usr/b\\040file type=file mode=644 uid=0 gid=0 uname=root gname=root size=6 \
+                        sha256digest=5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03
+";
+        assert_eq!(mtree(&pkgdir, &ownership).unwrap(), expected);
+    }
+}
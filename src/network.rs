@@ -1,18 +1,26 @@
 use failure::Error;
 use git2::build::{CheckoutBuilder, RepoBuilder};
 use git2::{self, FetchOptions, RemoteCallbacks, Repository};
+use hyper;
 use indicatif::{ProgressBar, ProgressStyle};
-use reqwest::header::{AcceptRanges, ByteRangeSpec, ContentLength, ContentRange, ContentRangeSpec,
-                      Headers, Range, RangeUnit};
-use reqwest::{self, Client};
+use reqwest::header::{ByteRangeSpec, ContentLength, ContentRange, ContentRangeSpec, ETag, Headers,
+                      Range};
+use reqwest::{self, Client, StatusCode};
 use url::Url;
 
+use std::collections::HashMap;
 use std::fs::{self, OpenOptions};
 use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
 use std::time::{Duration, Instant};
 
-use package::{BuildFile, PackageError};
+use checksum::ChecksumKind;
+use package::{BuildFile, PackageError, Source};
 use progress::{InitFn, IterFn};
+use sandbox::{Sandbox, SandboxError};
+use signature::{Keyring, SignatureError};
 use util::{self, path_to_string, UtilError};
 
 use super::Config;
@@ -34,48 +42,159 @@ pub enum NetworkError {
     #[fail(display = "could not read metadata for file '{}': {}", _0, _1)]
     Metadata(String, #[cause] io::Error),
 
+    #[fail(display = "could not remove file '{}': {}", _0, _1)]
+    RemoveFile(String, #[cause] io::Error),
+
+    #[fail(display = "could not copy '{}' to '{}': {}", _0, _1, _2)]
+    Copy(String, String, #[cause] io::Error),
+
+    #[fail(display = "could not move '{}' to '{}': {}", _0, _1, _2)]
+    Rename(String, String, #[cause] io::Error),
+
     #[fail(display = "failed to download data from '{}': {}", _0, _1)]
     Download(Url, #[cause] io::Error),
 
     #[fail(display = "failed to write to '{}': {}", _0, _1)]
     Write(String, #[cause] io::Error),
 
+    #[fail(display = "could not compute checksum of '{}': {}", _0, _1)]
+    Checksum(String, #[cause] io::Error),
+
+    #[fail(display = "{} checksum mismatch for '{}' (expected {}, found {})", _0, _1, _2, _3)]
+    ChecksumMismatch(ChecksumKind, String, String, String),
+
     #[fail(display = "'{}' is an invalid source file path", _0)]
     InvalidSource(String),
 
+    #[fail(display = "package '{}' is missing {} of its sources", _0, _1)]
+    MissingSources(String, usize),
+
+    #[fail(display = "{}", _0)]
+    Signature(#[cause] SignatureError),
+
     #[fail(display = "{}", _0)]
     Util(#[cause] UtilError),
 
     #[fail(display = "{}", _0)]
     Package(#[cause] PackageError),
 
+    #[fail(display = "'{}' has not been downloaded, which is required when offline", _0)]
+    Offline(String),
+
+    #[fail(display = "could not download '{}' from any of its {} URLs (the last one failed with: {})", _0, _1, _2)]
+    AllUrls(String, usize, String),
+
+    #[fail(display = "no URLs to download '{}' from, as there are no mirrors listed for '{}'", _0, _1)]
+    NoUrls(String, String),
+
     #[fail(display = "invalid scheme for the URL '{}'", _0)]
     UnknownScheme(Url),
 
     #[fail(display = "unknown fragment type for the URL '{}'", _0)]
     UnknownFragment(Url),
 
+    #[fail(display = "could not execute command '{}': {}", _0, _1)]
+    Spawn(String, #[cause] io::Error),
+
+    #[fail(display = "package '{}' failed on fetch command '{}': {}", _0, _1, _2)]
+    Fetch(String, String, String),
+
+    #[fail(display = "{}", _0)]
+    Sandbox(#[cause] SandboxError),
+
     #[fail(display = "failed to download '{}': {}", _0, _1)]
     Git(String, #[cause] git2::Error),
 
     #[fail(display = "failed to download '{}': {}", _0, _1)]
-    Reqwest(String, #[cause] reqwest::Error),
+    Reqwest(String, #[cause] Box<reqwest::Error>),
+
+    #[fail(display = "the server rejected resuming the download of '{}'", _0)]
+    RangeNotSatisfiable(Url),
+}
+
+impl NetworkError {
+    // whether trying again later might help, i.e. the connection failed or the server had trouble
+    // (as opposed to the file not existing or us being unable to write it)
+    fn is_transient(&self) -> bool {
+        match *self {
+            NetworkError::Download(..) => true,
+            NetworkError::Reqwest(_, ref err) => match err.status() {
+                Some(status) => {
+                    status.is_server_error() || status == StatusCode::TooManyRequests
+                }
+                None => is_transient_request(err),
+            },
+            _ => false,
+        }
+    }
 }
 
 pub(crate) struct Downloader {
     client: Client,
+    verify: bool,
+}
+
+// what an HTTP server told us about a file
+struct RemoteFile {
+    length: Option<u64>,
+    etag: Option<String>,
+}
+
+impl RemoteFile {
+    fn from_headers(headers: &Headers) -> Self {
+        Self {
+            length: headers.get::<ContentLength>().map(|h| h.0),
+            // weak ETags don't promise that the file is byte-for-byte identical
+            etag: headers
+                .get::<ETag>()
+                .filter(|etag| !etag.weak)
+                .map(|etag| etag.tag().to_string()),
+        }
+    }
+
+    // whether both describe the same file, as far as we can tell
+    fn matches(&self, other: &RemoteFile) -> bool {
+        // anything the server did not tell us both times can't be compared
+        fn same<T: PartialEq>(a: &Option<T>, b: &Option<T>) -> bool {
+            a.is_none() || b.is_none() || a == b
+        }
+        same(&self.length, &other.length) && same(&self.etag, &other.etag)
+    }
+}
+
+// how a response to a request for the rest of a partial download carries on from it
+#[derive(Debug, PartialEq)]
+enum Continuation {
+    // the server refused the range (as the partial file is longer than the file on the server)
+    Rejected,
+    // the server ignored the range and sent the whole file (or there was nothing to resume)
+    Whole,
+    // the server sent the rest of the file, which ends at the given byte
+    Rest(u64),
+    // the server sent some other part of the file, which can't be appended to the partial file
+    Unexpected,
 }
 
 impl Downloader {
     const WAIT_TIME_MILLIS: u64 = 250;
     const WAIT_TIME_NANOS: u32 = Self::WAIT_TIME_MILLIS as u32 * 1_000_000;
+    // failed transfers are retried after 1, 2, 4, ... seconds (up to a bit over a minute)
+    const RETRY_DELAY_SECS: u64 = 1;
+    const MAX_RETRY_SHIFT: u32 = 6;
 
     pub fn new() -> Self {
         Self {
             client: Client::new(),
+            verify: true,
         }
     }
 
+    // whether downloaded files should be checked against the checksums in their build files
+    pub fn set_verify(&mut self, verify: bool) -> &mut Self {
+        self.verify = verify;
+        self
+    }
+
     pub fn download_setup<'a>(
         &'a self,
         _config: &Config,
@@ -99,15 +218,36 @@ impl Downloader {
                 fs::create_dir_all(&download_dir)
                     .map_err(|e| NetworkError::CreateDir(path_to_string(&download_dir), e))?;
 
-                for (i, url) in pkg.source().iter().enumerate() {
+                let mut failed = 0;
+                for i in 0..pkg.source().len() {
                     progbar.set_prefix(&format!("{}/{}", pkg.name(), i + 1));
                     progbar.set_position(0);
 
-                    if let Err(f) = self.download(&progbar, pkg, config, url) {
+                    if let Err(f) = self.download(progbar, pkg, config, i) {
+                        failed += 1;
                         add_error(f.into());
                     }
                 }
 
+                // the errors above explain what went wrong, but the package can't go on without
+                // every one of its sources
+                if failed > 0 {
+                    Err(NetworkError::MissingSources(pkg.name().to_string(), failed))?;
+                }
+
+                if !pkg.validpgpkeys().is_empty() {
+                    progbar.set_prefix(pkg.name());
+                    self.verify_signatures(pkg, config)?;
+                }
+
+                // when offline, whatever the fetch step gets should have been fetched already
+                if let Some(fetch) = pkg.fetch() {
+                    if !config.offline {
+                        progbar.set_prefix(pkg.name());
+                        self.run_fetch(progbar, pkg, config, fetch)?;
+                    }
+                }
+
                 Ok(())
             };
             inner().map_err(|e| e.into())
@@ -116,23 +256,298 @@ impl Downloader {
         (Box::new(init_fn), Box::new(iter_fn))
     }
 
-    // TODO: check if download of correct file has already occurred and ensure the downloaded file
-    //       is complete/not corrupted (to do so we would need some sort of checksums).  if so, we
-    //       can skip that download
+    // runs the package's fetch commands in its source directory, which has to be the last thing
+    // the download step does as the commands might need any of the sources
+    fn run_fetch(
+        &self,
+        progbar: &ProgressBar,
+        pkg: &BuildFile,
+        config: &Config,
+        fetch: &[String],
+    ) -> Result<(), NetworkError> {
+        let srcdir = pkg.download_dir(config);
+        let srcdir = srcdir
+            .canonicalize()
+            .map_err(|e| NetworkError::Canonicalize(path_to_string(&srcdir), e))?;
+
+        for cmd in fetch {
+            progbar.set_message(cmd.lines().next().unwrap_or(""));
+
+            let mut sh = Command::new("/bin/sh");
+            if config.sandbox {
+                // the commands need the network but may only write to the source directory.
+                // dependencies have not been built yet, so there is no sysroot to run them in
+                let mut sandbox = Sandbox::new(config.toolchain_root, pkg.sandbox_dir(config));
+                sandbox
+                    .add_writable(&srcdir)
+                    .set_network(true)
+                    .apply(&mut sh, &srcdir)
+                    .map_err(NetworkError::Sandbox)?;
+            }
+
+            let output = sh.arg("-c")
+                .arg(cmd)
+                .current_dir(&srcdir)
+                .env("srcdir", &srcdir)
+                .envs(pkg.env())
+                .stdin(Stdio::null())
+                .output()
+                .map_err(|e| NetworkError::Spawn(cmd.to_string(), e))?;
+            if !output.status.success() {
+                // the last line is usually the one explaining what went wrong
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                let reason = match stderr.lines().rfind(|line| !line.trim().is_empty()) {
+                    Some(line) => line.trim().to_string(),
+                    None => output.status.to_string(),
+                };
+                Err(NetworkError::Fetch(pkg.name().to_string(), cmd.to_string(), reason))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // downloads the source at the given index from each of its URLs in turn until one of them
+    // works and matches the checksums given in the build file (if any)
     fn download(
         &self,
         progbar: &ProgressBar,
         pkg: &BuildFile,
         config: &Config,
-        src_item: &str,
+        index: usize,
+    ) -> Result<(), NetworkError> {
+        let src = &pkg.source()[index];
+        let checksums = if self.verify {
+            pkg.checksums(index)
+        } else {
+            vec![]
+        };
+
+        let urls = source_urls(&config.mirrors, src)?;
+        let mut errors = vec![];
+        for url in &urls {
+            progbar.set_position(0);
+            match self.download_from(progbar, pkg, config, src, url, &checksums) {
+                Ok(()) => {
+                    // partial downloads from the URLs that failed are of no use anymore
+                    let filename = src.filename().map_err(NetworkError::Package)?;
+                    for url in &urls {
+                        self.remove_part(&self.part_path(pkg, config, &filename, url))?;
+                    }
+                    return Ok(());
+                }
+                Err(f) => errors.push(f),
+            }
+        }
+
+        // no need to wrap the error if there was nothing else to try
+        if errors.len() == 1 {
+            Err(errors.pop().unwrap())
+        } else {
+            let filename = src.filename().map_err(NetworkError::Package)?;
+            let last = errors.pop().map(|f| f.to_string()).unwrap_or_default();
+            Err(NetworkError::AllUrls(filename, urls.len(), last))
+        }
+    }
+
+    fn download_from(
+        &self,
+        progbar: &ProgressBar,
+        pkg: &BuildFile,
+        config: &Config,
+        src: &Source,
+        url: &str,
+        checksums: &[(ChecksumKind, &str)],
     ) -> Result<(), NetworkError> {
-        if let Ok(mut url) = Url::parse(src_item) {
-            let filename = BuildFile::file_path(src_item).map_err(|e| NetworkError::Package(e))?;
+        let filepath = pkg.file_download_path(config, src)
+            .map_err(NetworkError::Package)?;
+
+        // a single HEAD request tells us the ETag that sources without checksums are cached under,
+        // the length that an earlier download of them is compared against, and whether a partial
+        // download left behind by an earlier run is still worth resuming
+        let filename = src.filename().map_err(NetworkError::Package)?;
+        let resumable = self.part_path(pkg, config, &filename, url).is_file();
+        let remote = if (checksums.is_empty() || resumable) && !config.offline && is_http(url) {
+            self.head(url)
+        } else {
+            None
+        };
+
+        // sources that were already downloaded for another package (or another version of this
+        // one) can be taken straight from the cache
+        let cache_path = self.cache_path(config, url, checksums, remote.as_ref());
+        if let Some(ref cache_path) = cache_path {
+            if self.verify_cached(cache_path, checksums) {
+                return self.copy_file(cache_path, &filepath);
+            }
+        }
+
+        self.fetch(progbar, pkg, config, src, url, checksums, remote.as_ref())?;
+
+        if !checksums.is_empty() {
+            if let Err(f) = self.verify(&filepath, checksums) {
+                // the next URL must not resume from (or be satisfied by) what this one sent
+                if filepath.is_file() {
+                    fs::remove_file(&filepath)
+                        .map_err(|e| NetworkError::RemoveFile(path_to_string(&filepath), e))?;
+                }
+                Err(f)?;
+            }
+        }
+
+        if let Some(cache_path) = cache_path {
+            self.add_to_cache(pkg, &filepath, &cache_path, checksums)?;
+        }
+
+        Ok(())
+    }
+
+    // where the given source is stored in the cache, which is keyed by its checksum or, failing
+    // that, by its URL and ETag (in which case we trust the server to change the ETag along with
+    // the file).  only files downloaded over HTTP are cached
+    fn cache_path(
+        &self,
+        config: &Config,
+        url: &str,
+        checksums: &[(ChecksumKind, &str)],
+        remote: Option<&RemoteFile>,
+    ) -> Option<PathBuf> {
+        if !is_http(url) {
+            return None;
+        }
+
+        if let Some(&(kind, sum)) = checksums.first() {
+            // the checksum becomes part of a path, so it had better not contain a slash
+            if !sum.chars().all(|ch| ch.is_ascii_hexdigit()) {
+                return None;
+            }
+            Some(config.cache_dir.join(kind.cache_name()).join(sum.to_lowercase()))
+        } else if let Some(etag) = remote.and_then(|remote| remote.etag.as_ref()) {
+            let key = ChecksumKind::Sha256.hash_bytes(format!("{}\n{}", url, etag).as_bytes());
+            Some(config.cache_dir.join("url").join(key))
+        } else {
+            if config.verbose {
+                let reason = if config.offline {
+                    "we are offline"
+                } else if remote.is_none() {
+                    "the HEAD request for it failed"
+                } else {
+                    "the server did not send a strong ETag"
+                };
+                let _ = util::display_warn(format_args!(
+                    "not using the cache for '{}' as it has no checksums and {}",
+                    url, reason
+                ));
+            }
+            None
+        }
+    }
+
+    // whether the cache holds an intact copy of a file.  entries keyed by an ETag have no
+    // checksums to be verified against, so the SHA-256 of their contents is stored next to them
+    fn verify_cached(&self, cache_path: &Path, checksums: &[(ChecksumKind, &str)]) -> bool {
+        if !cache_path.is_file() {
+            return false;
+        }
+        if !checksums.is_empty() {
+            return self.verify(cache_path, checksums).is_ok();
+        }
+        match fs::read_to_string(digest_path(cache_path)) {
+            Ok(sum) => self.verify(cache_path, &[(ChecksumKind::Sha256, sum.trim())]).is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    // adds a freshly downloaded file (and, if it has no checksums, its digest) to the cache
+    fn add_to_cache(
+        &self,
+        pkg: &BuildFile,
+        filepath: &Path,
+        cache_path: &Path,
+        checksums: &[(ChecksumKind, &str)],
+    ) -> Result<(), NetworkError> {
+        // unwrap() is fine as the cache path always has a parent
+        let cache_dir = cache_path.parent().unwrap();
+        fs::create_dir_all(cache_dir)
+            .map_err(|e| NetworkError::CreateDir(path_to_string(cache_dir), e))?;
+
+        if checksums.is_empty() {
+            let sum = ChecksumKind::Sha256.hash_file(filepath)
+                .map_err(|e| NetworkError::Checksum(path_to_string(filepath), e))?;
+            self.write_to_cache(pkg, &digest_path(cache_path), |temp_path| {
+                fs::write(temp_path, &sum)
+                    .map_err(|e| NetworkError::Write(path_to_string(temp_path), e))
+            })?;
+        }
+        self.write_to_cache(pkg, cache_path, |temp_path| self.copy_file(filepath, temp_path))
+    }
+
+    // writes a file in the cache by way of a temporary file, so that another package never sees
+    // it partially written
+    fn write_to_cache<F>(&self, pkg: &BuildFile, path: &Path, write: F) -> Result<(), NetworkError>
+    where
+        F: FnOnce(&Path) -> Result<(), NetworkError>,
+    {
+        // unwrap() is fine as paths in the cache always have a file name
+        let mut temp_name = path.file_name().unwrap().to_os_string();
+        temp_name.push(format!(".{}.tmp", pkg.name()));
+        let temp_path = path.with_file_name(temp_name);
+        write(&temp_path)?;
+        fs::rename(&temp_path, path).map_err(|e| {
+            NetworkError::Rename(path_to_string(&temp_path), path_to_string(path), e)
+        })
+    }
+
+    // copies the file at from to to (replacing whatever is there).  files are never linked in or
+    // out of the cache, as writing to the package's copy (which ends up in its build directory)
+    // would then change the cached one as well
+    fn copy_file(&self, from: &Path, to: &Path) -> Result<(), NetworkError> {
+        if fs::symlink_metadata(to).is_ok() {
+            fs::remove_file(to).map_err(|e| NetworkError::RemoveFile(path_to_string(to), e))?;
+        }
+        fs::copy(from, to)
+            .map(|_| ())
+            .map_err(|e| NetworkError::Copy(path_to_string(from), path_to_string(to), e))
+    }
+
+    // checks each signature source against the file it signs using only the keys from the
+    // keyring directory that are listed in the build file
+    fn verify_signatures(&self, pkg: &BuildFile, config: &Config) -> Result<(), NetworkError> {
+        let keyring = Keyring::create(&pkg.gnupg_dir(config), config.keyring_dir)
+            .map_err(NetworkError::Signature)?;
+
+        for (sig_idx, signed_idx) in pkg.signatures() {
+            let (sig, signed) = (&pkg.source()[sig_idx], &pkg.source()[signed_idx]);
+            let sig_path = pkg.file_download_path(config, sig)
+                .map_err(NetworkError::Package)?;
+            let signed_path = pkg.file_download_path(config, signed)
+                .map_err(NetworkError::Package)?;
+
+            keyring
+                .verify(&sig_path, &signed_path, pkg.validpgpkeys())
+                .map_err(NetworkError::Signature)?;
+        }
+
+        Ok(())
+    }
+
+    fn fetch(
+        &self,
+        progbar: &ProgressBar,
+        pkg: &BuildFile,
+        config: &Config,
+        src: &Source,
+        url: &str,
+        checksums: &[(ChecksumKind, &str)],
+        remote: Option<&RemoteFile>,
+    ) -> Result<(), NetworkError> {
+        let filename = src.filename().map_err(NetworkError::Package)?;
+        if let Ok(mut url) = Url::parse(url) {
 
             match url.scheme() {
                 "http" | "https" => {
                     // as we require git URLs to be prefixed with "git+", this should be fine
-                    self.download_http(progbar, pkg, config, &url, &filename)
+                    self.download_http(progbar, pkg, config, &url, &filename, checksums, remote)
                 }
                 "git+http" | "git+https" | "git" | "git+ssh" => {
                     // can only be git (if it's a valid source URL)
@@ -152,7 +567,7 @@ impl Downloader {
                 .canonicalize()
                 .map_err(|e| NetworkError::Canonicalize(path_to_string(pkgbuild_dir), e))?;
 
-            let filepath = pkgbuild_dir.join(pkg.parent_dir()).join(src_item);
+            let filepath = pkgbuild_dir.join(pkg.parent_dir()).join(url);
             let filepath = filepath
                 .canonicalize()
                 .map_err(|e| NetworkError::Canonicalize(path_to_string(&filepath), e))?;
@@ -160,8 +575,8 @@ impl Downloader {
             // ensure that the build file isn't trying to use system files as "sources"
             if filepath.starts_with(pkgbuild_dir) {
                 // TODO: probably check for config.clobber
-                let target_path = pkg.download_dir(config);
-                util::copy_dir(&filepath, &target_path).map_err(|e| NetworkError::Util(e))
+                let target_path = pkg.download_dir(config).join(&filename);
+                util::copy_dir_as(&filepath, &target_path).map_err(NetworkError::Util)
             } else {
                 Err(NetworkError::InvalidSource(path_to_string(&filepath)))
             }
@@ -227,6 +642,12 @@ impl Downloader {
         url.set_fragment(None);
 
         let download_path = pkg.download_dir(config).join(filename);
+        if config.offline {
+            // the repository has to be usable as is, fragment and all
+            let repo = Repository::open(&download_path)
+                .map_err(|_| NetworkError::Offline(url.to_string()))?;
+            return self.checkout_fragment(pkg, url, &repo, fragment);
+        }
         if download_path.exists() {
             if !config.clobber {
                 if let Ok(repo) = Repository::open(&download_path) {
@@ -297,77 +718,187 @@ impl Downloader {
         config: &Config,
         url: &Url,
         filename: &str,
+        checksums: &[(ChecksumKind, &str)],
+        remote: Option<&RemoteFile>,
     ) -> Result<(), NetworkError> {
-        const BUF_SIZE: usize = 32 * 1024;
-
         let filepath = pkg.download_dir(config).join(filename);
-        let mut open_opts = OpenOptions::new();
-        let mut headers = Headers::new();
+        // the download is only moved into place once it's complete, so a file at filepath is
+        // never a partial download
+        let partpath = self.part_path(pkg, config, filename, url.as_str());
+
+        // whatever we already have is checked against the checksums afterwards
+        if config.offline {
+            if filepath.is_file() {
+                return Ok(());
+            }
+            Err(NetworkError::Offline(url.to_string()))?;
+        }
 
         if filepath.exists() && !config.clobber {
-            if let Some(length) = self.supports_range(url) {
-                // get metadata for file so we can 1. get the size of the file for Range and 2. see if
-                // the server has a newer version of the file (which would mean we need to download
-                // from scratch)
+            // if the file we already have matches the checksums, there is no need to even contact
+            // the server
+            if !checksums.is_empty() && self.verify(&filepath, checksums).is_ok() {
+                return Ok(());
+            }
+
+            // without checksums we can only assume that we have the correct file if it's the
+            // same size as the one on the server, but if the checksums did not match, the file is
+            // corrupted and must be downloaded again
+            if checksums.is_empty() {
                 let metadata = fs::metadata(&filepath)
                     .map_err(|e| NetworkError::Metadata(path_to_string(&filepath), e))?;
-
-                let filelen = metadata.len();
-                if length == filelen {
-                    // we (most likely) have the correct file, so we are done
+                if remote.and_then(|remote| remote.length) == Some(metadata.len()) {
                     return Ok(());
-                } else if filelen < length {
-                    // TODO: handle error (basically if anything fails here we should just download from
-                    //       scratch)
-                    //let create_time = metadata.created().or_else(|_| metadata.modified()).unwrap();
-                    // subtract 60 seconds to satisfy If-Range's date validator
-                    //let range_date = create_time - Duration::from_secs(60 * 60 * 24);
-
-                    // FIXME: not sure how to get If-Range to work correctly
-                    //headers.set(LastModified(range_date.into()));
-                    //headers.set(IfRange::Date(create_time.into()));
-                    headers.set(Range::Bytes(vec![ByteRangeSpec::AllFrom(filelen)]));
-
-                    open_opts.append(true);
                 }
             }
         }
 
-        // we don't set any headers unless Content-Range is supported, so this is fine
-        if headers.len() == 0 {
-            // either the file doesn't exist or ranges aren't supported by the server, so just
-            // trash any file that already exists
-            open_opts.create(true).truncate(true).write(true);
+        // a partial download can only be continued if the file on the server is still the one it
+        // was started with (which we can't tell if the HEAD request failed, but then the server
+        // will most likely not let us resume anyway)
+        let stale = match remote {
+            Some(remote) => !self.part_info(&partpath).matches(remote),
+            None => false,
+        };
+        if config.clobber || stale {
+            self.remove_part(&partpath)?;
+        }
+
+        // each retry picks up where the last attempt left off
+        let mut attempt = 0;
+        let mut restarted = false;
+        while let Err(f) = self.transfer(progbar, pkg, url, &partpath) {
+            // transfer() has already removed the partial file, so we start over from the
+            // beginning right away, but only once in case the server is simply broken
+            if let NetworkError::RangeNotSatisfiable(..) = f {
+                if !restarted {
+                    restarted = true;
+                    continue;
+                }
+            }
+            if attempt >= config.retries || !f.is_transient() {
+                Err(f)?;
+            }
+            attempt += 1;
+            let delay = Self::RETRY_DELAY_SECS << (attempt - 1).min(Self::MAX_RETRY_SHIFT);
+            thread::sleep(Duration::from_secs(delay));
+        }
+
+        fs::rename(&partpath, &filepath).map_err(|e| {
+            NetworkError::Rename(path_to_string(&partpath), path_to_string(&filepath), e)
+        })?;
+        self.remove_part(&partpath)
+    }
+
+    // where a partial download of the file from the given URL is kept.  as mirrors might not
+    // agree on the contents of the file, each URL gets its own, so that a download is never
+    // resumed with data from another server
+    fn part_path(&self, pkg: &BuildFile, config: &Config, filename: &str, url: &str) -> PathBuf {
+        let key = ChecksumKind::Sha256.hash_bytes(url.as_bytes());
+        pkg.download_dir(config)
+            .join(format!("{}.{}.part", filename, &key[..16]))
+    }
+
+    // what the server told us about the file when the partial download was started, which is
+    // stored next to it
+    fn part_info(&self, partpath: &Path) -> RemoteFile {
+        let contents = fs::read_to_string(info_path(partpath)).unwrap_or_default();
+        let mut lines = contents.lines();
+        RemoteFile {
+            length: lines.next().and_then(|line| line.parse().ok()),
+            etag: lines.next().map(|line| line.to_string()),
         }
+    }
 
-        let mut resp = self.client
+    fn write_part_info(&self, partpath: &Path, info: &RemoteFile) -> Result<(), NetworkError> {
+        let path = info_path(partpath);
+        let length = info.length.map(|length| length.to_string()).unwrap_or_default();
+        let contents = format!("{}\n{}\n", length, info.etag.as_ref().map_or("", |etag| &etag[..]));
+        fs::write(&path, contents).map_err(|e| NetworkError::Write(path_to_string(&path), e))
+    }
+
+    // removes a partial download (if there is one) along with what we know about it
+    fn remove_part(&self, partpath: &Path) -> Result<(), NetworkError> {
+        for path in &[partpath.to_path_buf(), info_path(partpath)] {
+            if path.is_file() {
+                fs::remove_file(path)
+                    .map_err(|e| NetworkError::RemoveFile(path_to_string(path), e))?;
+            }
+        }
+        Ok(())
+    }
+
+    // downloads the file at the given URL into partpath, resuming from whatever partpath already
+    // contains if the server supports it
+    fn transfer(
+        &self,
+        progbar: &ProgressBar,
+        pkg: &BuildFile,
+        url: &Url,
+        partpath: &Path,
+    ) -> Result<(), NetworkError> {
+        const BUF_SIZE: usize = 32 * 1024;
+
+        let offset = fs::metadata(partpath).map(|meta| meta.len()).unwrap_or(0);
+        let mut headers = Headers::new();
+        if offset > 0 {
+            headers.set(Range::Bytes(vec![ByteRangeSpec::AllFrom(offset)]));
+        }
+
+        let resp = self.client
             .get(url.as_str())
             .headers(headers)
             .send()
-            .and_then(|res| res.error_for_status())
-            .map_err(|e| NetworkError::Reqwest(pkg.name().to_string(), e))?;
+            .map_err(|e| NetworkError::Reqwest(pkg.name().to_string(), Box::new(e)))?;
 
-        // XXX: will range ever be None?
-        if let Some(&ContentRange(ContentRangeSpec::Bytes {
-            range: Some((from, to)),
-            instance_length: _,
-        })) = resp.headers().get::<ContentRange>()
-        {
-            progbar.set_style(self.bar_style());
+        let continuation = continuation(offset, resp.status(), resp.headers());
+        // the partial file is longer than the file on the server, so it can't be right
+        if continuation == Continuation::Rejected {
+            self.remove_part(partpath)?;
+            Err(NetworkError::RangeNotSatisfiable(url.clone()))?;
+        }
 
-            progbar.set_length(to);
-            progbar.set_position(from);
-        } else if let Some(&ContentLength(length)) = resp.headers().get::<ContentLength>() {
-            progbar.set_style(self.bar_style());
+        let mut resp = resp.error_for_status()
+            .map_err(|e| NetworkError::Reqwest(pkg.name().to_string(), Box::new(e)))?;
 
-            progbar.set_length(length);
-        } else {
-            progbar.set_style(self.spinner_style());
+        let mut resumed = false;
+        match continuation {
+            Continuation::Rest(to) => {
+                progbar.set_style(self.bar_style());
+
+                progbar.set_length(to + 1);
+                progbar.set_position(offset);
+                resumed = true;
+            }
+            Continuation::Unexpected => {
+                // appending anything else would corrupt the file, so start over next time
+                self.remove_part(partpath)?;
+                let err = io::Error::other("unexpected Content-Range");
+                Err(NetworkError::Download(url.clone(), err))?;
+            }
+            _ => {
+                if let Some(&ContentLength(length)) = resp.headers().get::<ContentLength>() {
+                    progbar.set_style(self.bar_style());
+
+                    progbar.set_length(length);
+                    progbar.set_position(0);
+                } else {
+                    progbar.set_style(self.spinner_style());
+                }
+            }
+        }
+        if !resumed {
+            let info = RemoteFile::from_headers(resp.headers());
+            self.write_part_info(partpath, &info)?;
         }
 
-        let file = open_opts
-            .open(&filepath)
-            .map_err(|e| NetworkError::TargetFile(path_to_string(&filepath), e))?;
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(resumed)
+            .truncate(!resumed)
+            .open(partpath)
+            .map_err(|e| NetworkError::TargetFile(path_to_string(partpath), e))?;
 
         let mut writer = BufWriter::new(file);
 
@@ -390,21 +921,43 @@ impl Downloader {
 
             writer
                 .write_all(&buffer[..n])
-                .map_err(|e| NetworkError::Write(path_to_string(&filepath), e))?;
+                .map_err(|e| NetworkError::Write(path_to_string(partpath), e))?;
         }
 
         progbar.inc(byte_count as u64);
 
+        writer
+            .flush()
+            .map_err(|e| NetworkError::Write(path_to_string(partpath), e))
+    }
+
+    fn verify(
+        &self,
+        filepath: &Path,
+        checksums: &[(ChecksumKind, &str)],
+    ) -> Result<(), NetworkError> {
+        for &(kind, expected) in checksums {
+            let actual = kind.hash_file(filepath)
+                .map_err(|e| NetworkError::Checksum(path_to_string(filepath), e))?;
+            if !actual.eq_ignore_ascii_case(expected) {
+                Err(NetworkError::ChecksumMismatch(
+                    kind,
+                    path_to_string(filepath),
+                    expected.to_string(),
+                    actual,
+                ))?;
+            }
+        }
+
         Ok(())
     }
 
-    fn supports_range(&self, url: &Url) -> Option<u64> {
-        self.client.head(url.as_str()).send().ok().and_then(|res| {
-            res.headers()
-                .get::<AcceptRanges>()
-                .map(|h| h.contains(&RangeUnit::Bytes) as u64)
-                .and(res.headers().get::<ContentLength>().map(|h| h.0))
-        })
+    fn head(&self, url: &str) -> Option<RemoteFile> {
+        let res = self.client.head(url).send().ok()?;
+        if !res.status().is_success() {
+            return None;
+        }
+        Some(RemoteFile::from_headers(res.headers()))
     }
 
     fn perform_gradually<F>(&self, last_check: &mut Instant, mut action: F)
@@ -444,3 +997,388 @@ impl Downloader {
         ProgressStyle::default_bar().template("{prefix:.bold.dim}: {msg}")
     }
 }
+
+// the URLs to try for the given source, with any URL whose scheme names a set of mirrors (e.g.
+// gnu://gcc/gcc.tar.xz) replaced by the same path on each mirror
+fn source_urls(
+    mirrors: &HashMap<String, Vec<String>>,
+    src: &Source,
+) -> Result<Vec<String>, NetworkError> {
+    let mut urls = vec![];
+    for url in src.urls() {
+        let mirrors = url.find("://")
+            .and_then(|idx| mirrors.get(&url[..idx]).map(|mirrors| (mirrors, &url[idx + 3..])));
+        match mirrors {
+            Some((mirrors, path)) => {
+                for mirror in mirrors {
+                    urls.push(format!("{}/{}", mirror.trim_end_matches('/'), path));
+                }
+            }
+            None => urls.push(url.clone()),
+        }
+    }
+
+    // every URL used a mirror without any entries in mirrors.yaml
+    if urls.is_empty() {
+        let filename = src.filename().map_err(NetworkError::Package)?;
+        let names = src.urls()
+            .iter()
+            .filter_map(|url| url.find("://").map(|idx| &url[..idx]))
+            .collect::<Vec<_>>();
+        Err(NetworkError::NoUrls(filename, names.join("', '")))?;
+    }
+    Ok(urls)
+}
+
+// servers that don't support ranges simply send the whole file again, so the response only
+// continues the partial download (of the given length) if it starts right where that ends
+fn continuation(offset: u64, status: StatusCode, headers: &Headers) -> Continuation {
+    if offset > 0 && status == StatusCode::RangeNotSatisfiable {
+        return Continuation::Rejected;
+    } else if status != StatusCode::PartialContent {
+        return Continuation::Whole;
+    }
+    match headers.get::<ContentRange>() {
+        Some(&ContentRange(ContentRangeSpec::Bytes {
+            range: Some((from, to)),
+            instance_length: _,
+        })) if from == offset =>
+        {
+            Continuation::Rest(to)
+        }
+        _ => Continuation::Unexpected,
+    }
+}
+
+fn is_http(url: &str) -> bool {
+    Url::parse(url)
+        .map(|url| url.scheme() == "http" || url.scheme() == "https")
+        .unwrap_or(false)
+}
+
+// whether a request failed because of the connection (rather than e.g. an invalid URL, a TLS
+// error, or a redirect loop).  hyper reports connection failures as (wrapped) I/O errors
+fn is_transient_request(err: &reqwest::Error) -> bool {
+    let err = match err.get_ref() {
+        Some(err) => err,
+        None => return false,
+    };
+    if let Some(err) = err.downcast_ref::<io::Error>() {
+        return is_transient_io(err);
+    }
+    match err.downcast_ref::<hyper::Error>() {
+        Some(hyper::Error::Io(err)) => is_transient_io(err),
+        Some(hyper::Error::Incomplete) | Some(hyper::Error::Timeout) => true,
+        _ => false,
+    }
+}
+
+fn is_transient_io(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::Interrupted
+    )
+}
+
+fn info_path(partpath: &Path) -> PathBuf {
+    let mut name = partpath.as_os_str().to_os_string();
+    name.push(".info");
+    PathBuf::from(name)
+}
+
+fn digest_path(cache_path: &Path) -> PathBuf {
+    let mut name = cache_path.as_os_str().to_os_string();
+    name.push(".sha256");
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile;
+
+    use std::cell::RefCell;
+    use std::net::TcpListener;
+
+    fn mirrors() -> HashMap<String, Vec<String>> {
+        let mut mirrors = HashMap::new();
+        mirrors.insert(
+            "gnu".to_string(),
+            vec![
+                "https://ftp.gnu.org/gnu".to_string(),
+                "https://mirrors.kernel.org/gnu/".to_string(),
+            ],
+        );
+        mirrors.insert("empty".to_string(), vec![]);
+        mirrors
+    }
+
+    fn urls(urls: &[&str]) -> Result<Vec<String>, NetworkError> {
+        let fields = format!(
+            "  source:\n    - url:\n{}",
+            urls.iter()
+                .map(|url| format!("        - {}\n", url))
+                .collect::<String>()
+        );
+        let pkg = BuildFile::parse_test("foo", &fields).unwrap();
+        source_urls(&mirrors(), &pkg.source()[0])
+    }
+
+    #[test]
+    fn plain_urls() {
+        assert_eq!(
+            urls(&["https://example.com/foo.tar.gz", "foo.tar.gz"]).unwrap(),
+            vec!["https://example.com/foo.tar.gz", "foo.tar.gz"]
+        );
+    }
+
+    #[test]
+    fn mirror_urls() {
+        assert_eq!(
+            urls(&["gnu://gcc/gcc-8.2.0/gcc-8.2.0.tar.xz"]).unwrap(),
+            vec![
+                "https://ftp.gnu.org/gnu/gcc/gcc-8.2.0/gcc-8.2.0.tar.xz",
+                "https://mirrors.kernel.org/gnu/gcc/gcc-8.2.0/gcc-8.2.0.tar.xz",
+            ]
+        );
+    }
+
+    #[test]
+    fn mirrors_between_urls() {
+        assert_eq!(
+            urls(&[
+                "https://example.com/make.tar.gz",
+                "gnu://make/make.tar.gz",
+                "unknown://make/make.tar.gz",
+                "empty://make/make.tar.gz",
+            ]).unwrap(),
+            vec![
+                "https://example.com/make.tar.gz",
+                "https://ftp.gnu.org/gnu/make/make.tar.gz",
+                "https://mirrors.kernel.org/gnu/make/make.tar.gz",
+                "unknown://make/make.tar.gz",
+            ]
+        );
+    }
+
+    #[test]
+    fn only_empty_mirrors() {
+        let res = urls(&["empty://make/make.tar.gz"]);
+        assert!(matches!(
+            res,
+            Err(NetworkError::NoUrls(ref filename, ref names)) if filename == "make.tar.gz" && names == "empty"
+        ));
+    }
+
+    fn content_range(from: u64, to: u64) -> Headers {
+        let mut headers = Headers::new();
+        headers.set(ContentRange(ContentRangeSpec::Bytes {
+            range: Some((from, to)),
+            instance_length: Some(to + 1),
+        }));
+        headers
+    }
+
+    #[test]
+    fn continue_with_rest() {
+        let headers = content_range(100, 199);
+        assert_eq!(
+            continuation(100, StatusCode::PartialContent, &headers),
+            Continuation::Rest(199)
+        );
+    }
+
+    #[test]
+    fn continue_with_whole_file() {
+        assert_eq!(continuation(0, StatusCode::Ok, &Headers::new()), Continuation::Whole);
+        assert_eq!(continuation(100, StatusCode::Ok, &Headers::new()), Continuation::Whole);
+        // ranges only matter for partial content
+        assert_eq!(continuation(100, StatusCode::Ok, &content_range(0, 199)), Continuation::Whole);
+    }
+
+    #[test]
+    fn continue_rejected() {
+        let status = StatusCode::RangeNotSatisfiable;
+        assert_eq!(continuation(100, status, &Headers::new()), Continuation::Rejected);
+        // without a range in the request, it's just another error
+        assert_eq!(continuation(0, status, &Headers::new()), Continuation::Whole);
+    }
+
+    #[test]
+    fn continue_unexpected() {
+        let status = StatusCode::PartialContent;
+        assert_eq!(continuation(100, status, &content_range(0, 199)), Continuation::Unexpected);
+        assert_eq!(continuation(100, status, &content_range(150, 199)), Continuation::Unexpected);
+        assert_eq!(continuation(100, status, &Headers::new()), Continuation::Unexpected);
+
+        let mut headers = Headers::new();
+        headers.set(ContentRange(ContentRangeSpec::Bytes {
+            range: None,
+            instance_length: Some(50),
+        }));
+        assert_eq!(continuation(100, status, &headers), Continuation::Unexpected);
+    }
+
+    #[test]
+    fn cache_entries_are_copies() {
+        let dir = tempfile::tempdir().unwrap();
+        let pkg = BuildFile::parse_test("foo", "  source: []\n").unwrap();
+        let downloader = Downloader::new();
+        let filepath = dir.path().join("foo.tar.gz");
+        let cache_path = dir.path().join("cache").join("url").join("key");
+        fs::write(&filepath, "contents").unwrap();
+        downloader.add_to_cache(&pkg, &filepath, &cache_path, &[]).unwrap();
+        assert!(downloader.verify_cached(&cache_path, &[]));
+
+        let srcpath = dir.path().join("bar.tar.gz");
+        downloader.copy_file(&cache_path, &srcpath).unwrap();
+        for path in &[&filepath, &srcpath] {
+            let mut file = OpenOptions::new().append(true).open(path).unwrap();
+            file.write_all(b" and more").unwrap();
+        }
+        assert_eq!(fs::read_to_string(&cache_path).unwrap(), "contents");
+        assert!(downloader.verify_cached(&cache_path, &[]));
+    }
+
+    #[test]
+    fn modified_cache_entries_are_not_used() {
+        let dir = tempfile::tempdir().unwrap();
+        let pkg = BuildFile::parse_test("foo", "  source: []\n").unwrap();
+        let downloader = Downloader::new();
+        let filepath = dir.path().join("foo.tar.gz");
+        let cache_path = dir.path().join("cache").join("url").join("key");
+        fs::write(&filepath, "contents").unwrap();
+        downloader.add_to_cache(&pkg, &filepath, &cache_path, &[]).unwrap();
+
+        fs::write(&cache_path, "something else").unwrap();
+        assert!(!downloader.verify_cached(&cache_path, &[]));
+        fs::write(&cache_path, "contents").unwrap();
+        fs::remove_file(digest_path(&cache_path)).unwrap();
+        assert!(!downloader.verify_cached(&cache_path, &[]));
+    }
+
+    // runs the download step in offline mode for a package with the given source once the given
+    // files have been downloaded, returning its result and any errors reported along the way
+    fn download_offline(
+        dir: &Path,
+        source: &str,
+        files: &[(&str, &str)],
+    ) -> (Result<(), Error>, Vec<Error>) {
+        let mut config = Config::test(dir);
+        config.offline = true;
+        let fields = format!("  source:\n    - {}\n", source);
+        let pkg = BuildFile::parse_test("foo", &fields).unwrap();
+        fs::create_dir_all(pkg.download_dir(&config)).unwrap();
+        for &(name, contents) in files {
+            fs::write(pkg.download_dir(&config).join(name), contents).unwrap();
+        }
+
+        let downloader = Downloader::new();
+        let (_, iter_fn) = downloader.download_setup(&config, &[]);
+        let errors = RefCell::new(vec![]);
+        let add_error = |err: Error| errors.borrow_mut().push(err);
+        let bar = ProgressBar::hidden();
+        let res = iter_fn(&config, &pkg, &bar, &bar, &add_error);
+        (res, errors.into_inner())
+    }
+
+    #[test]
+    fn offline_uses_downloaded_sources() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = "https://example.com/foo.tar.gz";
+        let (res, errors) = download_offline(dir.path(), source, &[("foo.tar.gz", "contents")]);
+        res.unwrap();
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn missing_sources_fail_the_package() {
+        let dir = tempfile::tempdir().unwrap();
+        let (res, errors) = download_offline(dir.path(), "https://example.com/foo.tar.gz", &[]);
+        assert!(matches!(
+            res.unwrap_err().downcast_ref::<NetworkError>(),
+            Some(NetworkError::MissingSources(_, 1))
+        ));
+        // the reason the source is missing is still reported separately
+        assert_eq!(errors.len(), 1);
+    }
+
+    // serves every connection with the given reply (or leaves it waiting if there is none) and
+    // returns the server's address
+    fn serve(reply: Option<&'static str>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut waiting = vec![];
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let _ = stream.read(&mut [0; 4096]);
+                match reply {
+                    Some(reply) => {
+                        let _ = stream.write_all(reply.as_bytes());
+                    }
+                    None => waiting.push(stream),
+                }
+            }
+        });
+        addr.to_string()
+    }
+
+    fn request_error(client: &Client, url: &str) -> NetworkError {
+        let err = match client.get(url).send() {
+            Ok(resp) => resp.error_for_status().unwrap_err(),
+            Err(err) => err,
+        };
+        NetworkError::Reqwest("foo".to_string(), Box::new(err))
+    }
+
+    #[test]
+    fn transient_server_errors() {
+        let client = Client::new();
+        let reply = "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n";
+        let url = format!("http://{}/", serve(Some(reply)));
+        assert!(request_error(&client, &url).is_transient());
+
+        let reply = "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n";
+        let url = format!("http://{}/", serve(Some(reply)));
+        assert!(!request_error(&client, &url).is_transient());
+    }
+
+    #[test]
+    fn transient_connection_errors() {
+        // nothing listens on the port once the listener is gone
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let url = format!("http://{}/", addr);
+        assert!(request_error(&Client::new(), &url).is_transient());
+
+        let client = Client::builder()
+            .timeout(Duration::from_millis(100))
+            .build()
+            .unwrap();
+        let url = format!("http://{}/", serve(None));
+        assert!(request_error(&client, &url).is_transient());
+    }
+
+    #[test]
+    fn permanent_request_errors() {
+        let client = Client::new();
+        assert!(!request_error(&client, "http://exa mple.com/").is_transient());
+
+        // the server answers the TLS handshake with plain HTTP
+        let reply = "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n";
+        let url = format!("https://{}/", serve(Some(reply)));
+        assert!(!request_error(&client, &url).is_transient());
+
+        let reply = "HTTP/1.1 302 Found\r\nLocation: /\r\nContent-Length: 0\r\n\r\n";
+        let url = format!("http://{}/", serve(Some(reply)));
+        assert!(!request_error(&client, &url).is_transient());
+    }
+}
@@ -4,21 +4,129 @@ use serde_yaml;
 use unicode_xid::UnicodeXID;
 use url::Url;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::ffi::OsStr;
 use std::fs::File;
-use std::io::BufReader;
-use std::path::{Path, PathBuf};
+use std::io::{BufReader, Read};
+use std::path::{Component, Path, PathBuf};
+
+use archive::Compression;
+use checksum::{ChecksumKind, SKIP};
+use signature;
+use util::path_to_string;
 
 use super::Config;
 
 #[derive(Debug, Fail)]
 pub enum PackageError {
-    #[fail(display = "could not determine file path from the URL: {}", _0)]
+    #[fail(display = "could not determine file path from the URL (try giving the source a filename): {}", _0)]
     UnknownFilePath(Url),
 
+    #[fail(display = "found a source without any URLs")]
+    NoUrls,
+
+    #[fail(display = "invalid filename '{}' for source '{}' (it must not contain '/')", _0, _1)]
+    InvalidFilename(String, String),
+
+    #[fail(display = "invalid subdir '{}' for source '{}' (it must be a relative path without '..')", _0, _1)]
+    InvalidSubdir(String, String),
+
+    #[fail(display = "multiple sources would be saved as '{}' (try giving them different filenames)", _0)]
+    DuplicateFilename(String),
+
+    #[fail(display = "patch '{}' is not the filename of any source", _0)]
+    UnknownPatch(String),
+
+    #[fail(display = "invalid dir '{}' for patch '{}' (it must be a relative path without '..')", _0, _1)]
+    InvalidPatchDir(String, String),
+
+    #[fail(display = "invalid key '{}' in validpgpkeys (it must be a full fingerprint)", _0)]
+    InvalidPgpKey(String),
+
+    #[fail(display = "validpgpkeys was given, but no source is a signature (ending in .sig or .asc) of another source")]
+    NoSignatures,
+
     #[fail(display = "the check step is required unless skip_check is true")]
     NeedsCheck,
+
+    #[fail(display = "skip_extract is no longer supported (set noextract on the sources instead)")]
+    SkipExtract,
+
+    #[fail(display = "expected {} entries in {} (one per source) but found {}", _0, _1, _2)]
+    ChecksumCount(usize, &'static str, usize),
+
+    #[fail(display = "the checksum for git source '{}' must be {}", _0, _1)]
+    GitChecksum(String, &'static str),
+}
+
+// flags that change how a package is built
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PackageOption {
+    // allow build commands to access the network (by default they only get a loopback interface)
+    Network,
+}
+
+// something to download (or copy from the directory containing the build file) in order to build
+// a package, which is written either as a map with the fields below or as just the URL (optionally
+// prefixed by "filename::", as in makepkg)
+#[derive(Clone, Debug)]
+pub struct Source {
+    // URLs or paths relative to the build file, which are tried in order until one of them works
+    // (the first one determines the default filename).  a URL such as gnu://gcc/gcc.tar.xz
+    // refers to the named set of mirrors given in the mirrors file
+    urls: Vec<String>,
+    // name to save the source as (by default, the last segment of the URL)
+    filename: Option<String>,
+    // directory within the build directory to extract the source into
+    subdir: Option<String>,
+    // whether to copy the source into the build directory as-is instead of extracting it
+    noextract: bool,
+}
+
+// a source that is applied using patch(1) before the prepare step rather than being extracted.
+// sources ending in .patch or .diff are treated as patches automatically, while the patches field
+// (a list of filenames or maps with the fields below) can include other sources and change how
+// each patch is applied
+#[derive(Clone, Debug)]
+pub struct Patch {
+    // name of the source within download_dir
+    file: String,
+    // number of leading components to strip from the paths in the patch (as in patch -p)
+    strip: u32,
+    // directory within the build directory to apply the patch in (by default, $name-$version if
+    // a source was extracted there and otherwise the build directory itself)
+    dir: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum PatchRaw {
+    File(String),
+    Map {
+        file: String,
+        strip: Option<u32>,
+        dir: Option<String>,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum SourceRaw {
+    Url(String),
+    Map {
+        url: UrlsRaw,
+        filename: Option<String>,
+        subdir: Option<String>,
+        noextract: Option<bool>,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum UrlsRaw {
+    One(String),
+    Many(Vec<String>),
 }
 
 #[derive(Debug, Default)]
@@ -36,11 +144,30 @@ struct Package {
     description: String,
     license: Vec<String>,
 
+    // names of other packages (runtime, build-time, and test-time dependencies respectively)
+    depends: Vec<String>,
+    makedepends: Vec<String>,
+    checkdepends: Vec<String>,
+    // dependencies that may be satisfied by an earlier stage of a bootstrap (e.g. the host's
+    // toolchain) rather than being built first, which allows breaking dependency cycles
+    bootstrap: Vec<String>,
+
     // files to download
-    source: Vec<String>,
-    skip_extract: Option<bool>,
+    source: Vec<Source>,
+    sha256sums: Option<Vec<String>>,
+    b2sums: Option<Vec<String>>,
+    // fingerprints of the keys trusted to sign the sources (signatures are only checked if given)
+    validpgpkeys: Vec<String>,
+    // the sources to apply as patches, in the order they are listed in source
+    patches: Vec<Patch>,
     skip_check: Option<bool>,
+    options: Vec<PackageOption>,
+    // overrides the compression format given on the command line
+    compression: Option<Compression>,
 
+    // run by the download step (which, unlike the others, has network access) to fetch anything
+    // else the build needs, such as the crates a Rust project depends on
+    fetch: Option<Vec<String>>,
     prepare: Option<Vec<String>>,
     build: Option<Vec<String>>,
     check: Option<Vec<String>>,
@@ -60,10 +187,23 @@ struct PackageRaw {
     description: String,
     license: Vec<String>,
 
-    source: Vec<String>,
+    depends: Option<Vec<String>>,
+    makedepends: Option<Vec<String>>,
+    checkdepends: Option<Vec<String>>,
+    bootstrap: Option<Vec<String>>,
+
+    source: Vec<SourceRaw>,
+    sha256sums: Option<Vec<String>>,
+    b2sums: Option<Vec<String>>,
+    validpgpkeys: Option<Vec<String>>,
+    patches: Option<Vec<PatchRaw>>,
+    // replaced by noextract on each source, but still read so that we can point that out
     skip_extract: Option<bool>,
     skip_check: Option<bool>,
+    options: Option<Vec<PackageOption>>,
+    compression: Option<Compression>,
 
+    fetch: Option<Vec<String>>,
     prepare: Option<Vec<String>>,
     build: Option<Vec<String>>,
     check: Option<Vec<String>>,
@@ -87,16 +227,54 @@ impl BuildFile {
             )
         })?;
 
-        let reader = BufReader::new(file);
+        Self::parse(Path::new(pkgname), BufReader::new(file))
+    }
+
+    // reads a build file that is found at the given path within pkgbuild_dir
+    pub(crate) fn parse<R: Read>(path: &Path, reader: R) -> Result<Self, Error> {
         let buildfile: BuildFileRaw = serde_yaml::from_reader(reader)?;
 
         let (mut env, mut package) = (buildfile.env, buildfile.package);
-
-        // if check hasn't been given and skip_extract is not present, error out
+        let mut source: Vec<Source> = package.source.drain(..).map(Source::from).collect();
+        let mut patches: Vec<Patch> = package
+            .patches
+            .take()
+            .unwrap_or_default()
+            .into_iter()
+            .map(Patch::from)
+            .collect();
+
+        // if check hasn't been given and skip_check is not present, error out
         if !package.skip_check.unwrap_or(false) && package.check.is_none() {
             Err(PackageError::NeedsCheck)?;
         }
 
+        if package.skip_extract.is_some() {
+            Err(PackageError::SkipExtract)?;
+        }
+
+        // every source needs a checksum (or SKIP) if the given type of checksum is used at all
+        for (kind, sums) in &[
+            (ChecksumKind::Sha256, &package.sha256sums),
+            (ChecksumKind::Blake2b, &package.b2sums),
+        ] {
+            if let Some(sums) = sums {
+                if sums.len() != source.len() {
+                    Err(PackageError::ChecksumCount(
+                        source.len(),
+                        kind.field_name(),
+                        sums.len(),
+                    ))?;
+                }
+                for (src, sum) in source.iter().zip(sums.iter()) {
+                    // we can't hash a repository, so the only valid entry is SKIP
+                    if src.is_git() && sum != SKIP {
+                        Err(PackageError::GitChecksum(src.url().to_string(), SKIP))?;
+                    }
+                }
+            }
+        }
+
         // FIXME: rewrite so that all the variables are substituted at once rather than one at a
         //        time the current way means that if a variable $var=$hi is substituted first, then
         //        if there is another variable $hi, it will get substituted as well (variables
@@ -112,8 +290,11 @@ impl BuildFile {
                 for license in &mut package.license {
                     *license = subst_vars(license, &key, val);
                 }
-                for src in &mut package.source {
-                    *src = subst_vars(src, &key, val);
+                for src in &mut source {
+                    src.subst_vars(&key, val);
+                }
+                for patch in &mut patches {
+                    patch.subst_vars(&key, val);
                 }
                 // don't need to do anything with prepare/build/install as we just attach the env
                 // vars as environment variables to `sh`
@@ -127,13 +308,62 @@ impl BuildFile {
         // TODO: support ${var} too
         package.description = subst_vars(&package.description, "$name", &package.name);
         package.description = subst_vars(&package.description, "$version", &package.version);
-        for src in &mut package.source {
-            *src = subst_vars(src, "$name", &package.name);
-            *src = subst_vars(src, "$version", &package.version);
+        for src in &mut source {
+            src.subst_vars("$name", &package.name);
+            src.subst_vars("$version", &package.version);
+        }
+        for patch in &mut patches {
+            patch.subst_vars("$name", &package.name);
+            patch.subst_vars("$version", &package.version);
         }
 
-        Ok(BuildFile {
-            path: PathBuf::from(pkgname),
+        // every source needs its own file, as otherwise they would overwrite each other
+        let mut filenames = HashSet::new();
+        for src in &source {
+            src.validate()?;
+            let filename = src.filename()?;
+            if !filenames.insert(filename.clone()) {
+                Err(PackageError::DuplicateFilename(filename))?;
+            }
+        }
+
+        for patch in &patches {
+            patch.validate()?;
+            if !filenames.contains(&patch.file) {
+                Err(PackageError::UnknownPatch(patch.file.clone()))?;
+            }
+        }
+        // fingerprints are often written in groups of four digits, so ignore the spaces
+        let validpgpkeys: Vec<String> = package
+            .validpgpkeys
+            .take()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|key| key.split_whitespace().collect::<String>().to_uppercase())
+            .collect();
+        for key in &validpgpkeys {
+            let valid = (key.len() == 40 || key.len() == 64)
+                && key.chars().all(|ch| ch.is_ascii_hexdigit());
+            if !valid {
+                Err(PackageError::InvalidPgpKey(key.clone()))?;
+            }
+        }
+
+        // patches are applied in the same order as the sources, as later patches often build on
+        // earlier ones (and a noextract source ending in .patch is left alone unless it's listed)
+        let mut ordered_patches = vec![];
+        for src in &source {
+            let filename = src.filename()?;
+            let is_patch = filename.ends_with(".patch") || filename.ends_with(".diff");
+            if let Some(patch) = patches.iter().find(|patch| patch.file == filename) {
+                ordered_patches.push(patch.clone());
+            } else if is_patch && !src.noextract() {
+                ordered_patches.push(Patch::new(filename));
+            }
+        }
+
+        let buildfile = BuildFile {
+            path: path.to_path_buf(),
 
             env: env,
             package: Package {
@@ -142,25 +372,63 @@ impl BuildFile {
                 description: package.description,
                 license: package.license,
 
-                source: package.source,
-                skip_extract: package.skip_extract,
+                depends: package.depends.unwrap_or_default(),
+                makedepends: package.makedepends.unwrap_or_default(),
+                checkdepends: package.checkdepends.unwrap_or_default(),
+                bootstrap: package.bootstrap.unwrap_or_default(),
+
+                source,
+                sha256sums: package.sha256sums,
+                b2sums: package.b2sums,
+                validpgpkeys,
+                patches: ordered_patches,
                 skip_check: package.skip_check,
+                options: package.options.unwrap_or_default(),
+                compression: package.compression,
 
+                fetch: package.fetch,
                 prepare: package.prepare,
                 build: package.build,
                 check: package.check,
                 install: package.install,
             },
-        })
+        };
+
+        // trusting keys without giving anything for them to verify is most likely a mistake
+        if !buildfile.validpgpkeys().is_empty() && buildfile.signatures().is_empty() {
+            Err(PackageError::NoSignatures)?;
+        }
+
+        Ok(buildfile)
     }
 
     // this is for testing the network code
     pub(crate) fn with_urls(urls: Vec<String>) -> Self {
         let mut buildfile = BuildFile::default();
-        buildfile.package.source = urls;
+        buildfile.package.source = urls.into_iter().map(Source::new).collect();
         buildfile
     }
 
+    // parses a build file for a package with the given name, with the given fields (indented as
+    // they would be within package) added to it.  the other required fields get placeholder
+    // values (and the check step is skipped) unless they are among the given fields
+    #[cfg(test)]
+    pub(crate) fn parse_test(name: &str, fields: &str) -> Result<Self, Error> {
+        let mut contents = format!("package:\n  name: {}\n", name);
+        for &(key, value) in &[
+            ("version", "1.2.3"),
+            ("description", "test"),
+            ("license", "[]"),
+            ("skip_check", "true"),
+        ] {
+            if !fields.lines().any(|line| line.starts_with(&format!("  {}:", key))) {
+                contents.push_str(&format!("  {}: {}\n", key, value));
+            }
+        }
+        contents.push_str(fields);
+        Self::parse(&Path::new(name).join("BUILD"), contents.as_bytes())
+    }
+
     // returns path to build file within pkgbuild_dir
     pub fn path(&self) -> &Path {
         &self.path
@@ -192,18 +460,100 @@ impl BuildFile {
         &self.package.license
     }
 
-    pub fn source(&self) -> &[String] {
+    pub fn depends(&self) -> &[String] {
+        &self.package.depends
+    }
+
+    pub fn makedepends(&self) -> &[String] {
+        &self.package.makedepends
+    }
+
+    pub fn checkdepends(&self) -> &[String] {
+        &self.package.checkdepends
+    }
+
+    // returns every package that needs to be built before this one can be built (i.e. every
+    // dependency not marked as bootstrap)
+    pub fn build_depends(&self) -> Vec<&str> {
+        self.package.build_depends()
+    }
+
+    // returns every dependency needed to use this package once built (excluding bootstrap ones)
+    pub fn runtime_depends(&self) -> Vec<&str> {
+        self.package.runtime_depends()
+    }
+
+    pub fn source(&self) -> &[Source] {
         &self.package.source
     }
 
-    pub fn skip_extract(&self) -> bool {
-        self.package.skip_extract.unwrap_or(false)
+    // full fingerprints (upper case and without spaces) of the keys trusted to sign the sources
+    pub fn validpgpkeys(&self) -> &[String] {
+        &self.package.validpgpkeys
+    }
+
+    // pairs of indices of sources that are detached signatures and the sources they sign (e.g.
+    // foo.tar.gz.sig and foo.tar.gz)
+    pub fn signatures(&self) -> Vec<(usize, usize)> {
+        let filenames: Vec<Option<String>> =
+            self.source().iter().map(|src| src.filename().ok()).collect();
+        let mut signatures = vec![];
+        for (sig_idx, sig_name) in filenames.iter().enumerate() {
+            let signed_name = sig_name.as_ref().and_then(|name| {
+                signature::EXTENSIONS
+                    .iter()
+                    .find(|ext| name.ends_with(*ext))
+                    .map(|ext| &name[..name.len() - ext.len()])
+            });
+            if let Some(signed_name) = signed_name {
+                let signed_idx = filenames.iter().position(|name| {
+                    name.as_ref().map(|name| name == signed_name).unwrap_or(false)
+                });
+                if let Some(signed_idx) = signed_idx {
+                    signatures.push((sig_idx, signed_idx));
+                }
+            }
+        }
+        signatures
+    }
+
+    pub fn patches(&self) -> &[Patch] {
+        &self.package.patches
+    }
+
+    pub fn is_patch(&self, src: &Source) -> bool {
+        match src.filename() {
+            Ok(filename) => self.package.patches.iter().any(|patch| patch.file == filename),
+            Err(_) => false,
+        }
+    }
+
+    // returns the checksums that the source at the given index should be verified against
+    pub fn checksums(&self, index: usize) -> Vec<(ChecksumKind, &str)> {
+        self.package.checksums(index)
+    }
+
+    // returns the types of checksums used by the build file
+    pub fn checksum_kinds(&self) -> Vec<ChecksumKind> {
+        self.package.checksum_kinds()
     }
 
     pub fn skip_check(&self) -> bool {
         self.package.skip_check.unwrap_or(false)
     }
 
+    pub fn has_option(&self, option: PackageOption) -> bool {
+        self.package.options.contains(&option)
+    }
+
+    pub fn compression(&self, config: &Config) -> Compression {
+        self.package.compression(config)
+    }
+
+    pub fn fetch(&self) -> Option<&Vec<String>> {
+        self.package.fetch.as_ref()
+    }
+
     pub fn prepare(&self) -> Option<&Vec<String>> {
         self.package.prepare.as_ref()
     }
@@ -248,6 +598,34 @@ impl BuildFile {
         self.package.archive_out_dir(config)
     }
 
+    pub fn sandbox_dir(&self, config: &Config) -> PathBuf {
+        self.package.sandbox_dir(config)
+    }
+
+    pub fn sysroot_dir(&self, config: &Config) -> PathBuf {
+        self.package.sysroot_dir(config)
+    }
+
+    pub fn uses_sysroot(&self, config: &Config) -> bool {
+        self.package.uses_sysroot(config)
+    }
+
+    pub fn root_dir(&self, config: &Config) -> PathBuf {
+        self.package.root_dir(config)
+    }
+
+    pub fn fakeroot_dir(&self, config: &Config) -> PathBuf {
+        self.package.fakeroot_dir(config)
+    }
+
+    pub fn gnupg_dir(&self, config: &Config) -> PathBuf {
+        self.package.gnupg_dir(config)
+    }
+
+    pub fn package_path(&self, config: &Config) -> PathBuf {
+        self.package.package_path(config)
+    }
+
     pub fn stdout_log(&self, config: &Config) -> PathBuf {
         self.package.stdout_log(config)
     }
@@ -260,11 +638,11 @@ impl BuildFile {
         self.package.info()
     }
 
-    pub fn file_path(src: &str) -> Result<String, PackageError> {
-        Package::file_path(src)
-    }
-
-    pub fn file_download_path(&self, config: &Config, src: &str) -> Result<PathBuf, PackageError> {
+    pub fn file_download_path(
+        &self,
+        config: &Config,
+        src: &Source,
+    ) -> Result<PathBuf, PackageError> {
         self.package.file_download_path(config, src)
     }
 }
@@ -308,21 +686,166 @@ impl Package {
         self.build_dir(config)
     }
 
+    pub fn sandbox_dir(&self, config: &Config) -> PathBuf {
+        self.base_dir(config).join("root")
+    }
+
+    pub fn sysroot_dir(&self, config: &Config) -> PathBuf {
+        self.base_dir(config).join("sysroot")
+    }
+
+    // packages with bootstrap dependencies expect them to be provided by an earlier stage (i.e.
+    // the toolchain root), so they cannot be built against their sysroot.  neither can packages
+    // without any dependencies, as their sysroot would be empty (lacking even a shell)
+    pub fn uses_sysroot(&self, config: &Config) -> bool {
+        config.sysroot && self.bootstrap.is_empty() && !self.build_depends().is_empty()
+    }
+
+    // the root filesystem the build commands see (which, without --sandbox, is simply /)
+    pub fn root_dir(&self, config: &Config) -> PathBuf {
+        if self.uses_sysroot(config) {
+            self.sysroot_dir(config)
+        } else {
+            config.toolchain_root.to_path_buf()
+        }
+    }
+
+    pub fn fakeroot_dir(&self, config: &Config) -> PathBuf {
+        self.base_dir(config).join("fakeroot")
+    }
+
+    pub fn gnupg_dir(&self, config: &Config) -> PathBuf {
+        self.base_dir(config).join("gnupg")
+    }
+
+    pub fn package_path(&self, config: &Config) -> PathBuf {
+        self.base_dir(config).join(format!(
+            "{}-{}{}",
+            self.name,
+            self.version,
+            self.compression(config).extension()
+        ))
+    }
+
+    pub fn compression(&self, config: &Config) -> Compression {
+        self.compression.unwrap_or(config.compression)
+    }
+
+    pub fn build_depends(&self) -> Vec<&str> {
+        let mut deps: Vec<&str> = self.depends
+            .iter()
+            .chain(self.makedepends.iter())
+            .map(|dep| &dep[..])
+            .collect();
+        // test dependencies are irrelevant if the tests are never run
+        if !self.skip_check.unwrap_or(false) {
+            deps.extend(self.checkdepends.iter().map(|dep| &dep[..]));
+        }
+        deps.retain(|dep| !self.bootstrap.iter().any(|bootstrap| bootstrap == dep));
+        deps.sort();
+        deps.dedup();
+        deps
+    }
+
+    pub fn runtime_depends(&self) -> Vec<&str> {
+        self.depends
+            .iter()
+            .filter(|dep| !self.bootstrap.contains(dep))
+            .map(|dep| &dep[..])
+            .collect()
+    }
+
     // TODO: colors and which section the package is in (e.g. core or testing)
     pub fn info(&self) -> String {
-        format!(
+        let mut info = format!(
             "{} {} {:?}\n{}",
             self.name,
             self.version,
             &self.license[..],
             self.description
-        )
+        );
+        for (kind, deps) in &[
+            ("depends", &self.depends),
+            ("makedepends", &self.makedepends),
+            ("checkdepends", &self.checkdepends),
+            ("bootstrap", &self.bootstrap),
+        ] {
+            if !deps.is_empty() {
+                info.push_str(&format!("\n{}: {}", kind, deps.join(" ")));
+            }
+        }
+        info
     }
 
-    // TODO: probably change sources to use an Either type (so we don't need to parse every time
-    //       this is called)
-    pub fn file_path(src: &str) -> Result<String, PackageError> {
-        if let Ok(url) = Url::parse(src) {
+    pub fn checksums(&self, index: usize) -> Vec<(ChecksumKind, &str)> {
+        let mut checksums = vec![];
+        for (kind, sums) in &[
+            (ChecksumKind::Sha256, &self.sha256sums),
+            (ChecksumKind::Blake2b, &self.b2sums),
+        ] {
+            if let Some(sum) = sums.as_ref().and_then(|sums| sums.get(index)) {
+                if sum != SKIP {
+                    checksums.push((*kind, &sum[..]));
+                }
+            }
+        }
+        checksums
+    }
+
+    pub fn checksum_kinds(&self) -> Vec<ChecksumKind> {
+        let mut kinds = vec![];
+        if self.sha256sums.is_some() {
+            kinds.push(ChecksumKind::Sha256);
+        }
+        if self.b2sums.is_some() {
+            kinds.push(ChecksumKind::Blake2b);
+        }
+        kinds
+    }
+
+    pub fn file_download_path(
+        &self,
+        config: &Config,
+        src: &Source,
+    ) -> Result<PathBuf, PackageError> {
+        Ok(self.download_dir(config).join(src.filename()?))
+    }
+}
+
+impl Source {
+    pub fn new(url: String) -> Self {
+        Self {
+            urls: vec![url],
+            filename: None,
+            subdir: None,
+            noextract: false,
+        }
+    }
+
+    // the first of the source's URLs, which is the one used in messages
+    pub fn url(&self) -> &str {
+        self.urls.first().map(|url| &url[..]).unwrap_or("")
+    }
+
+    pub fn urls(&self) -> &[String] {
+        &self.urls
+    }
+
+    pub fn subdir(&self) -> Option<&Path> {
+        self.subdir.as_ref().map(Path::new)
+    }
+
+    pub fn noextract(&self) -> bool {
+        self.noextract
+    }
+
+    // the name of the file (or directory) that the source is saved as within download_dir
+    pub fn filename(&self) -> Result<String, PackageError> {
+        if let Some(ref filename) = self.filename {
+            return Ok(filename.clone());
+        }
+
+        if let Ok(url) = Url::parse(self.url()) {
             let url_err = || PackageError::UnknownFilePath(url.clone());
 
             let filename = url.path_segments().ok_or_else(url_err)?.last().unwrap();
@@ -332,12 +855,144 @@ impl Package {
 
             Ok(filename.to_string())
         } else {
-            Ok(src.to_string())
+            // local files are copied into download_dir without the directories leading up to them
+            Ok(Path::new(self.url())
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_else(|| self.url().to_string()))
         }
     }
 
-    pub fn file_download_path(&self, config: &Config, src: &str) -> Result<PathBuf, PackageError> {
-        Ok(self.download_dir(config).join(Package::file_path(src)?))
+    pub fn is_git(&self) -> bool {
+        Url::parse(self.url())
+            .map(|url| url.scheme() == "git" || url.scheme().starts_with("git+"))
+            .unwrap_or(false)
+    }
+
+    fn subst_vars(&mut self, key: &str, value: &str) {
+        for url in &mut self.urls {
+            *url = subst_vars(url, key, value);
+        }
+        if let Some(ref mut filename) = self.filename {
+            *filename = subst_vars(filename, key, value);
+        }
+        if let Some(ref mut subdir) = self.subdir {
+            *subdir = subst_vars(subdir, key, value);
+        }
+    }
+
+    // makes sure the source stays within download_dir and the build directory
+    fn validate(&self) -> Result<(), PackageError> {
+        if self.urls.is_empty() {
+            Err(PackageError::NoUrls)?;
+        }
+        if let Some(ref filename) = self.filename {
+            if filename.is_empty() || filename.contains('/') || filename == "." || filename == ".."
+            {
+                Err(PackageError::InvalidFilename(filename.clone(), self.url().to_string()))?;
+            }
+        }
+        if let Some(subdir) = self.subdir() {
+            let valid = subdir
+                .components()
+                .all(|comp| matches!(comp, Component::Normal(_) | Component::CurDir));
+            if !valid {
+                Err(PackageError::InvalidSubdir(path_to_string(subdir), self.url().to_string()))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Patch {
+    pub fn new(file: String) -> Self {
+        Self {
+            file,
+            strip: 1,
+            dir: None,
+        }
+    }
+
+    pub fn file(&self) -> &str {
+        &self.file
+    }
+
+    pub fn strip(&self) -> u32 {
+        self.strip
+    }
+
+    pub fn dir(&self) -> Option<&Path> {
+        self.dir.as_ref().map(Path::new)
+    }
+
+    fn subst_vars(&mut self, key: &str, value: &str) {
+        self.file = subst_vars(&self.file, key, value);
+        if let Some(ref mut dir) = self.dir {
+            *dir = subst_vars(dir, key, value);
+        }
+    }
+
+    // like the subdir of a source, the patch must not touch anything outside the build directory
+    fn validate(&self) -> Result<(), PackageError> {
+        if let Some(dir) = self.dir() {
+            let valid = dir
+                .components()
+                .all(|comp| matches!(comp, Component::Normal(_) | Component::CurDir));
+            if !valid {
+                Err(PackageError::InvalidPatchDir(path_to_string(dir), self.file.clone()))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl From<PatchRaw> for Patch {
+    fn from(raw: PatchRaw) -> Self {
+        match raw {
+            PatchRaw::File(file) => Self::new(file),
+            PatchRaw::Map { file, strip, dir } => Self {
+                file,
+                strip: strip.unwrap_or(1),
+                dir,
+            },
+        }
+    }
+}
+
+impl From<SourceRaw> for Source {
+    fn from(raw: SourceRaw) -> Self {
+        match raw {
+            SourceRaw::Url(src) => {
+                // a name before "::" can't contain a slash or colon, so URLs like
+                // http://[::1]/file are left alone
+                if let Some(idx) = src.find("::") {
+                    let name = &src[..idx];
+                    if !name.is_empty() && !name.contains(['/', ':']) {
+                        return Self {
+                            urls: vec![src[idx + 2..].to_string()],
+                            filename: Some(name.to_string()),
+                            subdir: None,
+                            noextract: false,
+                        };
+                    }
+                }
+                Self::new(src)
+            }
+            SourceRaw::Map {
+                url,
+                filename,
+                subdir,
+                noextract,
+            } => Self {
+                urls: match url {
+                    UrlsRaw::One(url) => vec![url],
+                    UrlsRaw::Many(urls) => urls,
+                },
+                filename,
+                subdir,
+                noextract: noextract.unwrap_or(false),
+            },
+        }
     }
 }
 
@@ -349,10 +1004,21 @@ impl Default for Package {
             description: String::default(),
             license: vec![],
 
+            depends: vec![],
+            makedepends: vec![],
+            checkdepends: vec![],
+            bootstrap: vec![],
+
             source: vec![],
-            skip_extract: None,
+            sha256sums: None,
+            b2sums: None,
+            validpgpkeys: vec![],
+            patches: vec![],
             skip_check: None,
+            options: vec![],
+            compression: None,
 
+            fetch: None,
             prepare: None,
             build: None,
             check: None,
@@ -373,3 +1039,420 @@ fn subst_vars(input: &str, key: &str, value: &str) -> String {
         acc
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile;
+
+    fn error(fields: &str) -> PackageError {
+        BuildFile::parse_test("foo", fields).unwrap_err().downcast().unwrap()
+    }
+
+    #[test]
+    fn needs_check() {
+        let contents = "package:
+  name: foo
+  version: 1.2.3
+  description: test
+  license: []
+  source: []
+";
+        let err = BuildFile::parse(Path::new("foo/BUILD"), contents.as_bytes()).unwrap_err();
+        let err: PackageError = err.downcast().unwrap();
+        assert!(matches!(err, PackageError::NeedsCheck), "unexpected error: {}", err);
+    }
+
+    #[test]
+    fn checksums_per_source() {
+        let pkg = BuildFile::parse_test(
+            "foo",
+            "  source:
+    - https://example.com/foo.tar.gz
+    - https://example.com/foo.conf
+  sha256sums:
+    - aaaa
+    - SKIP
+  b2sums:
+    - bbbb
+    - cccc
+",
+        ).unwrap();
+        assert_eq!(pkg.checksum_kinds(), vec![ChecksumKind::Sha256, ChecksumKind::Blake2b]);
+        assert_eq!(
+            pkg.checksums(0),
+            vec![(ChecksumKind::Sha256, "aaaa"), (ChecksumKind::Blake2b, "bbbb")]
+        );
+        assert_eq!(pkg.checksums(1), vec![(ChecksumKind::Blake2b, "cccc")]);
+    }
+
+    #[test]
+    fn checksum_count() {
+        let err = error(
+            "  source:
+    - https://example.com/foo.tar.gz
+    - https://example.com/foo.conf
+  b2sums:
+    - bbbb
+",
+        );
+        assert!(
+            matches!(err, PackageError::ChecksumCount(2, "b2sums", 1)),
+            "unexpected error: {}",
+            err
+        );
+    }
+
+    #[test]
+    fn git_checksums() {
+        for url in &["git://example.com/foo.git", "git+https://example.com/foo.git"] {
+            let fields = format!(
+                "  source:
+    - {}
+  sha256sums:
+    - aaaa
+",
+                url
+            );
+            let err = error(&fields);
+            assert!(
+                matches!(err, PackageError::GitChecksum(ref src, SKIP) if src == url),
+                "unexpected error for {}: {}",
+                url,
+                err
+            );
+        }
+        let fields = "  source:
+    - git+https://example.com/foo.git
+  sha256sums:
+    - SKIP
+";
+        assert!(BuildFile::parse_test("foo", fields).is_ok());
+    }
+
+    fn filenames(pkg: &BuildFile) -> Vec<String> {
+        pkg.source().iter().map(|src| src.filename().unwrap()).collect()
+    }
+
+    #[test]
+    fn source_urls() {
+        let pkg = BuildFile::parse_test(
+            "foo",
+            "  source:
+    - https://example.com/foo-$version.tar.gz
+    - https://example.com/download?file=foo.patch
+    - files/foo.conf
+",
+        ).unwrap();
+        assert_eq!(pkg.source()[0].url(), "https://example.com/foo-1.2.3.tar.gz");
+        assert_eq!(filenames(&pkg), vec!["foo-1.2.3.tar.gz", "download", "foo.conf"]);
+    }
+
+    #[test]
+    fn source_with_filename() {
+        let pkg = BuildFile::parse_test(
+            "foo",
+            "  source:
+    - $name.tar.gz::https://example.com/v$version.tar.gz
+    - http://[::1]/bar.tar.gz
+    - ::https://example.com/baz.tar.gz
+",
+        ).unwrap();
+        let urls: Vec<&str> = pkg.source().iter().map(|src| src.url()).collect();
+        assert_eq!(
+            urls,
+            vec![
+                "https://example.com/v1.2.3.tar.gz",
+                "http://[::1]/bar.tar.gz",
+                "::https://example.com/baz.tar.gz",
+            ]
+        );
+        assert_eq!(filenames(&pkg)[..2], ["foo.tar.gz", "bar.tar.gz"]);
+    }
+
+    #[test]
+    fn source_map() {
+        let pkg = BuildFile::parse_test(
+            "foo",
+            "  source:
+    - url: https://example.com/foo.tar.gz
+      filename: $name-$version.tar.gz
+      subdir: vendor/$name
+    - url: https://example.com/foo.tar.gz
+",
+        ).unwrap();
+        let src = &pkg.source()[0];
+        assert_eq!(src.filename().unwrap(), "foo-1.2.3.tar.gz");
+        assert_eq!(src.subdir(), Some(Path::new("vendor/foo")));
+        assert_eq!(pkg.source()[1].subdir(), None);
+    }
+
+    #[test]
+    fn invalid_filenames() {
+        for filename in &["''", "dir/foo.tar.gz", ".", ".."] {
+            let fields = format!(
+                "  source:
+    - url: https://example.com/foo.tar.gz
+      filename: {}
+",
+                filename
+            );
+            let err = error(&fields);
+            assert!(
+                matches!(err, PackageError::InvalidFilename(..)),
+                "unexpected error for filename {}: {}",
+                filename,
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_subdirs() {
+        for subdir in &["..", "src/../..", "/usr/src"] {
+            let fields = format!(
+                "  source:
+    - url: https://example.com/foo.tar.gz
+      subdir: {}
+",
+                subdir
+            );
+            let err = error(&fields);
+            assert!(
+                matches!(err, PackageError::InvalidSubdir(..)),
+                "unexpected error for subdir {}: {}",
+                subdir,
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn duplicate_filenames() {
+        let err = error(
+            "  source:
+    - https://example.com/v1/foo.tar.gz
+    - https://example.com/v2/foo.tar.gz
+",
+        );
+        assert!(
+            matches!(err, PackageError::DuplicateFilename(ref name) if name == "foo.tar.gz"),
+            "unexpected error: {}",
+            err
+        );
+        assert!(
+            BuildFile::parse_test(
+                "foo",
+                "  source:
+    - https://example.com/v1/foo.tar.gz
+    - foo-2.tar.gz::https://example.com/v2/foo.tar.gz
+",
+            ).is_ok()
+        );
+    }
+
+    #[test]
+    fn noextract() {
+        let pkg = BuildFile::parse_test(
+            "foo",
+            "  source:
+    - https://example.com/foo.tar.gz
+    - url: https://example.com/bar.tar.gz
+      noextract: true
+",
+        ).unwrap();
+        let noextract: Vec<bool> = pkg.source().iter().map(|src| src.noextract()).collect();
+        assert_eq!(noextract, vec![false, true]);
+    }
+
+    #[test]
+    fn skip_extract() {
+        let err = error("  skip_extract: false\n  source: []\n");
+        assert!(matches!(err, PackageError::SkipExtract), "unexpected error: {}", err);
+    }
+
+    fn patch_files(pkg: &BuildFile) -> Vec<&str> {
+        pkg.patches().iter().map(|patch| patch.file()).collect()
+    }
+
+    #[test]
+    fn patches_in_source_order() {
+        let pkg = BuildFile::parse_test(
+            "foo",
+            "  source:
+    - https://example.com/foo.tar.gz
+    - https://example.com/extra.txt
+    - https://example.com/fix.patch
+    - https://example.com/other.diff
+    - url: https://example.com/docs.patch
+      noextract: true
+  patches:
+    - other.diff
+    - file: extra.txt
+      strip: 0
+      dir: $name-$version/src
+",
+        ).unwrap();
+        assert_eq!(patch_files(&pkg), vec!["extra.txt", "fix.patch", "other.diff"]);
+
+        let strips: Vec<u32> = pkg.patches().iter().map(|patch| patch.strip()).collect();
+        assert_eq!(strips, vec![0, 1, 1]);
+        assert_eq!(pkg.patches()[0].dir(), Some(Path::new("foo-1.2.3/src")));
+        assert_eq!(pkg.patches()[1].dir(), None);
+    }
+
+    #[test]
+    fn listed_noextract_patch() {
+        let pkg = BuildFile::parse_test(
+            "foo",
+            "  source:
+    - url: https://example.com/docs.patch
+      noextract: true
+  patches:
+    - docs.patch
+",
+        ).unwrap();
+        assert_eq!(patch_files(&pkg), vec!["docs.patch"]);
+    }
+
+    #[test]
+    fn unknown_patch() {
+        let err = error(
+            "  source:
+    - https://example.com/fix.patch
+  patches:
+    - fix.diff
+",
+        );
+        assert!(
+            matches!(err, PackageError::UnknownPatch(ref file) if file == "fix.diff"),
+            "unexpected error: {}",
+            err
+        );
+    }
+
+    #[test]
+    fn invalid_patch_dirs() {
+        for dir in &["..", "src/../..", "/usr/src"] {
+            let fields = format!(
+                "  source:
+    - https://example.com/fix.patch
+  patches:
+    - file: fix.patch
+      dir: {}
+",
+                dir
+            );
+            let err = error(&fields);
+            assert!(
+                matches!(err, PackageError::InvalidPatchDir(..)),
+                "unexpected error for dir {}: {}",
+                dir,
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn validpgpkeys() {
+        let pkg = BuildFile::parse_test(
+            "foo",
+            "  source:
+    - https://example.com/foo.tar.gz
+    - https://example.com/foo.tar.gz.sig
+    - https://example.com/bar.tar.gz.asc
+    - https://example.com/foo.conf
+  validpgpkeys:
+    - 0123 4567 89ab CDEF 0123  4567 89AB cdef 0123 4567
+",
+        ).unwrap();
+        assert_eq!(pkg.validpgpkeys(), ["0123456789ABCDEF0123456789ABCDEF01234567"]);
+        assert_eq!(pkg.signatures(), vec![(1, 0)]);
+    }
+
+    #[test]
+    fn invalid_pgp_keys() {
+        // short key ids and fingerprints with anything but hex digits can't be trusted
+        for key in &["89ABCDEF01234567", "0123456789ABCDEF0123456789ABCDEF0123456G"] {
+            let fields = format!(
+                "  source:
+    - https://example.com/foo.tar.gz
+    - https://example.com/foo.tar.gz.sig
+  validpgpkeys:
+    - {}
+",
+                key
+            );
+            let err = error(&fields);
+            assert!(
+                matches!(err, PackageError::InvalidPgpKey(ref invalid) if invalid == key),
+                "unexpected error for {}: {}",
+                key,
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn no_signatures() {
+        let err = error(
+            "  source:
+    - https://example.com/foo.tar.gz
+    - https://example.com/bar.tar.gz.sig
+  validpgpkeys:
+    - 0123456789ABCDEF0123456789ABCDEF01234567
+",
+        );
+        assert!(matches!(err, PackageError::NoSignatures), "unexpected error: {}", err);
+    }
+
+    #[test]
+    fn source_url_lists() {
+        let pkg = BuildFile::parse_test(
+            "foo",
+            "  source:
+    - url:
+        - https://example.com/$name.tar.gz
+        - gnu://$name/$name.tar.gz
+    - url: [foo.conf]
+",
+        ).unwrap();
+        assert_eq!(
+            pkg.source()[0].urls(),
+            ["https://example.com/foo.tar.gz", "gnu://foo/foo.tar.gz"]
+        );
+        assert_eq!(pkg.source()[0].url(), "https://example.com/foo.tar.gz");
+        assert_eq!(pkg.source()[1].urls(), ["foo.conf"]);
+        assert_eq!(filenames(&pkg), vec!["foo.tar.gz", "foo.conf"]);
+    }
+
+    #[test]
+    fn no_urls() {
+        let err = error("  source:\n    - url: []\n");
+        assert!(matches!(err, PackageError::NoUrls), "unexpected error: {}", err);
+    }
+
+    #[test]
+    fn fetch_commands() {
+        let pkg = BuildFile::parse_test("foo", "  source: []\n  fetch:\n    - cargo fetch\n").unwrap();
+        assert_eq!(pkg.fetch(), Some(&vec!["cargo fetch".to_string()]));
+        let pkg = BuildFile::parse_test("foo", "  source: []\n").unwrap();
+        assert_eq!(pkg.fetch(), None);
+    }
+
+    #[test]
+    fn sysroot_needs_dependencies() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config::test(dir.path());
+        config.sysroot = true;
+
+        let uses_sysroot = |fields: &str| {
+            let pkg = BuildFile::parse_test("foo", &format!("  source: []\n{}", fields)).unwrap();
+            pkg.uses_sysroot(&config)
+        };
+        assert!(uses_sysroot("  depends: [glibc]\n"));
+        assert!(uses_sysroot("  makedepends: [gcc]\n"));
+        assert!(!uses_sysroot(""));
+        assert!(!uses_sysroot("  makedepends: [gcc]\n  bootstrap: [gcc]\n"));
+    }
+}
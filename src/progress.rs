@@ -1,15 +1,15 @@
 use crossbeam;
 use crossbeam_utils;
-use crossbeam::queue::SegQueue as Queue;
 use failure::{Error, Fail};
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt;
 use std::fs;
 use std::io;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::{self, RecvTimeoutError};
-use std::sync::Mutex;
+use std::sync::{Condvar, Mutex};
 use std::time::Duration;
 
 use config::Config;
@@ -23,6 +23,23 @@ pub enum ProgressError {
 
     #[fail(display = "could not create directory '{}': {}", _0, _1)]
     CreateDir(String, #[cause] io::Error),
+
+    #[fail(display = "skipping '{}' as its dependency '{}' failed", _0, _1)]
+    DependencyFailed(String, String),
+}
+
+// the state of a package that has left the pipeline
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum PackageState {
+    Done,
+    Failed,
+}
+
+// the packages waiting for or being worked on by each step, shared by every worker
+struct Pipeline<'b> {
+    queues: Vec<VecDeque<&'b BuildFile>>,
+    working: Vec<usize>,
+    states: HashMap<String, PackageState>,
 }
 
 #[derive(Debug)]
@@ -58,6 +75,10 @@ pub struct Progress<'a> {
     bar_count: usize,
     init_fns: Vec<&'a InitFn<'a>>,
     iter_fns: Vec<&'a IterFn<'a>>,
+    // whether each step must wait for a package's dependencies to finish every step first
+    ordered: Vec<bool>,
+    // the dependencies of each package (limited to the packages we are working on)
+    deps: HashMap<String, Vec<String>>,
 }
 
 impl<'a> Progress<'a> {
@@ -70,16 +91,41 @@ impl<'a> Progress<'a> {
             bar_count = bar_count.min(build_num as usize + 1);
         }
 
+        let names: HashSet<&str> = pkgs.iter().map(|pkg| pkg.name()).collect();
+        let deps = pkgs.iter()
+            .map(|pkg| {
+                let deps = pkg.build_depends()
+                    .into_iter()
+                    .filter(|dep| names.contains(dep))
+                    .map(|dep| dep.to_string())
+                    .collect();
+                (pkg.name().to_string(), deps)
+            })
+            .collect();
+
         Self {
             bar_count: bar_count.max(2),
             init_fns: vec![],
             iter_fns: vec![],
+            ordered: vec![],
+            deps,
         }
     }
 
     pub fn add_step(&mut self, init: &'a InitFn<'a>, iter: &'a IterFn<'a>) -> &mut Self {
         self.init_fns.push(init);
         self.iter_fns.push(iter);
+        self.ordered.push(false);
+        self
+    }
+
+    // make the most recently added step wait until all of a package's dependencies have made it
+    // through every step before working on the package.  NOTE: this must be the last step, as the
+    // workers waiting in it are not going to move any dependencies along in later steps
+    pub fn order_by_dependencies(&mut self) -> &mut Self {
+        if let Some(ordered) = self.ordered.last_mut() {
+            *ordered = true;
+        }
         self
     }
 
@@ -99,16 +145,18 @@ impl<'a> Progress<'a> {
         let (multibar, total_bar) = Self::create_multibar(config);
         let total_bar = &total_bar;
 
-        let first_queue = Queue::new();
-        for buildfile in iter {
-            first_queue.push(buildfile);
-        }
-        let mut queues = vec![first_queue];
-        for _ in 1..self.iter_fns.len() {
-            queues.push(Queue::new());
-        }
+        let mut queues = vec![VecDeque::new(); self.iter_fns.len()];
+        queues[0].extend(iter);
+        let pipeline = Mutex::new(Pipeline {
+            queues,
+            working: vec![0; self.iter_fns.len()],
+            states: HashMap::new(),
+        });
+        let pipeline = &pipeline;
+        // signalled whenever a package leaves a step
+        let changed = Condvar::new();
+        let changed = &changed;
 
-        let queues = &queues[..];
         let init_fns = &self.init_fns[..];
         let iter_fns = &self.iter_fns[..];
 
@@ -141,7 +189,7 @@ impl<'a> Progress<'a> {
                 s.spawn(move |_| {
                     let progbar = &bars[i];
                     self.progress_handler(
-                        config, init_fns, iter_fns, queues, errors, total_bar, progbar,
+                        config, init_fns, iter_fns, pipeline, changed, errors, total_bar, progbar,
                     );
 
                     progbar.finish_with_message("Done");
@@ -173,7 +221,8 @@ impl<'a> Progress<'a> {
         config: &Config,
         init_fns: &[&'a InitFn<'a>],
         iter_fns: &[&'a IterFn<'a>],
-        queues: &[Queue<&'b BuildFile>],
+        pipeline: &Mutex<Pipeline<'b>>,
+        changed: &Condvar,
         errors: &Mutex<Vec<Error>>,
         total_bar: &ProgressBar,
         progbar: &ProgressBar,
@@ -183,47 +232,134 @@ impl<'a> Progress<'a> {
         init_fns[current_queue](total_bar, progbar);
 
         loop {
-            if let Some(buildfile) = queues[current_queue].try_pop() {
-                let add_error = |err: Error| {
-                    let mut errors = errors.lock().unwrap();
-                    errors.push(err);
-                    total_bar.set_message(&errors.len().to_string());
-
-                    if config.fail_fast {
-                        // TODO: figure out a way to exit immediately
+            let next = self.next_package(current_queue, pipeline, changed);
+            let (buildfile, failed_dep) = match next {
+                Some(next) => next,
+                None => {
+                    current_queue += 1;
+                    if current_queue == iter_fns.len() {
+                        break;
                     }
-                };
 
-                let builddir = buildfile.base_dir(config);
-                if !builddir.exists() {
-                    if let Err(f) = fs::create_dir(&builddir) {
-                        let nerr = ProgressError::CreateDir(path_to_string(&builddir), f).into();
-                        add_error(nerr);
-                        continue;
-                    }
+                    init_fns[current_queue](total_bar, progbar);
+                    continue;
                 }
+            };
+
+            let add_error = |err: Error| {
+                let mut errors = errors.lock().unwrap();
+                errors.push(err);
+                total_bar.set_message(&errors.len().to_string());
 
-                if let Err(f) =
+                if config.fail_fast {
+                    // TODO: figure out a way to exit immediately
+                }
+            };
+
+            let result: Result<(), Error> = if let Some(dep) = failed_dep {
+                Err(ProgressError::DependencyFailed(buildfile.name().to_string(), dep).into())
+            } else {
+                let builddir = buildfile.base_dir(config);
+                if !builddir.exists() {
+                    fs::create_dir(&builddir).map_err(|f| {
+                        ProgressError::CreateDir(path_to_string(&builddir), f).into()
+                    })
+                } else {
+                    Ok(())
+                }.and_then(|()| {
                     iter_fns[current_queue](config, buildfile, progbar, total_bar, &add_error)
-                {
+                })
+            };
+            let failed = match result {
+                Ok(()) => false,
+                Err(f) => {
                     add_error(f);
-                } else {
-                    if current_queue + 1 < queues.len() {
-                        queues[current_queue + 1].push(buildfile);
-                    } else {
-                        // XXX: is it clearer if the total bar increases even on failure?
-                        total_bar.inc(1);
-                    }
+                    true
                 }
+            };
+
+            let mut pipeline = pipeline.lock().unwrap();
+            pipeline.working[current_queue] -= 1;
+            let name = buildfile.name().to_string();
+            if failed {
+                pipeline.states.insert(name, PackageState::Failed);
+            } else if current_queue + 1 < iter_fns.len() {
+                pipeline.queues[current_queue + 1].push_back(buildfile);
             } else {
-                current_queue += 1;
-                if current_queue == queues.len() {
-                    break;
+                pipeline.states.insert(name, PackageState::Done);
+                // XXX: is it clearer if the total bar increases even on failure?
+                total_bar.inc(1);
+            }
+            changed.notify_all();
+        }
+    }
+
+    // waits for a package in the given step that can be worked on, which is returned along with
+    // the name of a dependency that failed (if any).  once the step has no packages left and none
+    // can reach it anymore (as every earlier step is done as well), None is returned
+    fn next_package<'b>(
+        &self,
+        step: usize,
+        pipeline: &Mutex<Pipeline<'b>>,
+        changed: &Condvar,
+    ) -> Option<(&'b BuildFile, Option<String>)> {
+        let mut guard = pipeline.lock().unwrap();
+        loop {
+            {
+                let pipeline = &mut *guard;
+                let mut next = None;
+                for (i, buildfile) in pipeline.queues[step].iter().enumerate() {
+                    if !self.ordered[step] {
+                        next = Some((i, None));
+                        break;
+                    }
+                    match self.check_dependencies(buildfile, &pipeline.states) {
+                        Ok(true) => {
+                            next = Some((i, None));
+                            break;
+                        }
+                        Ok(false) => {}
+                        Err(dep) => {
+                            next = Some((i, Some(dep)));
+                            break;
+                        }
+                    }
+                }
+                if let Some((i, failed_dep)) = next {
+                    let buildfile = pipeline.queues[step].remove(i).unwrap();
+                    pipeline.working[step] += 1;
+                    return Some((buildfile, failed_dep));
                 }
 
-                init_fns[current_queue](total_bar, progbar);
+                let finished = (0..step + 1).all(|i| pipeline.queues[i].is_empty())
+                    && (0..step).all(|i| pipeline.working[i] == 0);
+                if finished {
+                    return None;
+                }
+            }
+
+            // either the packages left in this step are waiting on their dependencies or packages
+            // are still on their way from earlier steps
+            guard = changed.wait(guard).unwrap();
+        }
+    }
+
+    // returns whether every dependency of the package has been completed or the name of a
+    // dependency that failed
+    fn check_dependencies(
+        &self,
+        buildfile: &BuildFile,
+        states: &HashMap<String, PackageState>,
+    ) -> Result<bool, String> {
+        let mut ready = true;
+        for dep in &self.deps[buildfile.name()] {
+            match states.get(dep) {
+                Some(PackageState::Done) => {}
+                Some(PackageState::Failed) => return Err(dep.clone()),
+                None => ready = false,
             }
         }
+        Ok(ready)
     }
 
     // spawn bar_count progress bars (with one being the total progress bar)
@@ -256,3 +392,76 @@ impl<'a> Progress<'a> {
             .template("{prefix}{wide_bar} {pos}/{len} packages ({msg} errors)")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use failure;
+    use std::thread;
+    use tempfile;
+
+    // runs a download-like step and then an ordered build-like step (which fails the packages
+    // named in fail) on the given packages, returning the order in which they were built and the
+    // number of errors
+    fn build_in_order(pkgs: &[(&str, &str)], fail: &[&str]) -> (Vec<String>, usize) {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config::test(dir.path());
+        config.verbose = true;
+        let pkgs: Vec<BuildFile> = pkgs.iter()
+            .map(|&(name, depends)| {
+                let fields = format!("  depends: [{}]\n  source: []\n", depends);
+                BuildFile::parse_test(name, &fields).unwrap()
+            })
+            .collect();
+
+        let built = Mutex::new(vec![]);
+        let init = |_: &ProgressBar, _: &ProgressBar| {};
+        let download =
+            |_: &Config, _: &BuildFile, _: &ProgressBar, _: &ProgressBar, _: &dyn Fn(Error)| Ok(());
+        let build = |_: &Config,
+                     pkg: &BuildFile,
+                     _: &ProgressBar,
+                     _: &ProgressBar,
+                     _: &dyn Fn(Error)| {
+            // give packages waiting on this one a chance to go ahead too early
+            thread::sleep(Duration::from_millis(20));
+            built.lock().unwrap().push(pkg.name().to_string());
+            if fail.contains(&pkg.name()) {
+                Err(failure::err_msg(format!("{} failed", pkg.name())))
+            } else {
+                Ok(())
+            }
+        };
+
+        // the packages are given in the wrong order to make sure they have to wait
+        let errors = match Progress::new(&config, &pkgs)
+            .add_step(&init, &download)
+            .add_step(&init, &build)
+            .order_by_dependencies()
+            .run(&config, pkgs.iter().rev())
+        {
+            Ok(()) => 0,
+            Err(e) => e.errs.len(),
+        };
+        (built.into_inner().unwrap(), errors)
+    }
+
+    #[test]
+    fn dependencies_built_first() {
+        let pkgs = [("a", ""), ("b", "a"), ("c", "a, b"), ("d", "")];
+        let (built, errors) = build_in_order(&pkgs, &[]);
+        let pos = |name| built.iter().position(|built| built == name).unwrap();
+        assert_eq!((built.len(), errors), (4, 0));
+        assert!(pos("a") < pos("b") && pos("b") < pos("c"));
+    }
+
+    #[test]
+    fn failed_dependency_skips_package() {
+        let pkgs = [("a", ""), ("b", "a"), ("c", "b"), ("d", "")];
+        let (mut built, errors) = build_in_order(&pkgs, &["a"]);
+        built.sort();
+        // b and c were skipped (with an error each) because a failed
+        assert_eq!(built, vec!["a", "d"]);
+        assert_eq!(errors, 3);
+    }
+}
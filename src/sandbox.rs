@@ -0,0 +1,819 @@
+use libc;
+use nix;
+use nix::fcntl::{self, OFlag};
+use nix::mount::{self, MsFlags};
+use nix::sched::{self, CloneFlags};
+use nix::sys::stat::Mode;
+use nix::sys::statvfs::{self, FsFlags};
+use nix::sys::wait::{self, WaitStatus};
+use nix::unistd::{self, ForkResult, Pid};
+
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use util::{nix_to_io, path_to_string};
+
+#[derive(Debug, Fail)]
+pub enum SandboxError {
+    #[fail(display = "could not create directory '{}': {}", _0, _1)]
+    CreateDir(String, #[cause] io::Error),
+
+    #[fail(display = "could not get real path for '{}': {}", _0, _1)]
+    Canonicalize(String, #[cause] io::Error),
+
+    #[fail(display = "could not read mount points from '{}': {}", _0, _1)]
+    MountInfo(String, #[cause] io::Error),
+
+    #[fail(display = "could not read directory '{}': {}", _0, _1)]
+    ReadDir(String, #[cause] io::Error),
+}
+
+// runs commands inside new user, mount, PID, and (unless network access is allowed) network
+// namespaces.  the root filesystem seen by the commands is a read-only copy of the toolchain
+// root with only the explicitly given directories being writable (mounted at the same paths they
+// have outside the sandbox)
+pub struct Sandbox {
+    // read-only base of the filesystem inside the sandbox
+    toolchain: PathBuf,
+    // empty directory in which the new root filesystem is assembled
+    root_dir: PathBuf,
+    writable: Vec<PathBuf>,
+    // whether the commands may access the host's network
+    network: bool,
+}
+
+impl Sandbox {
+    const MOUNTINFO: &'static str = "/proc/self/mountinfo";
+    // the only devices bound from the host into the sandbox's /dev
+    const DEVICES: &'static [&'static str] = &["null", "zero", "full", "random", "urandom", "tty"];
+    // symlinks created in the sandbox's /dev (and what they point to)
+    const DEV_LINKS: &'static [(&'static str, &'static str)] = &[
+        ("fd", "/proc/self/fd"),
+        ("stdin", "/proc/self/fd/0"),
+        ("stdout", "/proc/self/fd/1"),
+        ("stderr", "/proc/self/fd/2"),
+        ("ptmx", "pts/ptmx"),
+    ];
+
+    pub fn new<P: AsRef<Path>, Q: AsRef<Path>>(toolchain: P, root_dir: Q) -> Self {
+        Self {
+            toolchain: toolchain.as_ref().to_path_buf(),
+            root_dir: root_dir.as_ref().to_path_buf(),
+            writable: vec![],
+            network: false,
+        }
+    }
+
+    pub fn add_writable<P: AsRef<Path>>(&mut self, path: P) -> &mut Self {
+        self.writable.push(path.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn set_network(&mut self, network: bool) -> &mut Self {
+        self.network = network;
+        self
+    }
+
+    // makes the given command run inside the sandbox with cur_dir as its working directory
+    pub fn apply(&self, cmd: &mut Command, cur_dir: &Path) -> Result<(), SandboxError> {
+        let setup = self.prepare(cur_dir)?;
+        // Setup::enter() does nothing that is unsafe to do between fork() and exec()
+        unsafe {
+            cmd.pre_exec(move || setup.enter());
+        }
+        Ok(())
+    }
+
+    // everything that requires allocation must be done here rather than after forking (as
+    // another thread might have been holding the allocator's lock when we forked)
+    fn prepare(&self, cur_dir: &Path) -> Result<Setup, SandboxError> {
+        let toolchain = canonicalize(&self.toolchain)?;
+
+        if !self.root_dir.exists() {
+            fs::create_dir_all(&self.root_dir)
+                .map_err(|e| SandboxError::CreateDir(path_to_string(&self.root_dir), e))?;
+        }
+        let root_dir = canonicalize(&self.root_dir)?;
+
+        let mut binds = vec![];
+        for dir in &self.writable {
+            let dir = canonicalize(dir)?;
+            let target = self.mount_point(&root_dir, &dir);
+            binds.push((dir, target));
+        }
+
+        // any mount points the toolchain root lacks are created on top of a tmpfs rather than in
+        // the toolchain root itself
+        let mut needed: Vec<PathBuf> = binds.iter().map(|(dir, _)| dir.clone()).collect();
+        needed.extend(["/dev", "/proc", "/tmp"].iter().map(PathBuf::from));
+        let (shadows, missing) = self.shadows(&toolchain, &root_dir, &needed)?;
+
+        // the directories might be hidden by one of the filesystems we mount (e.g. if the build
+        // directory is in /tmp), in which case we need to recreate the mount points
+        let mut bind_dirs = vec![];
+        for (_, target) in &binds {
+            let mut dirs: Vec<PathBuf> = target
+                .ancestors()
+                .take_while(|dir| *dir != root_dir)
+                .map(|dir| dir.to_path_buf())
+                .collect();
+            dirs.reverse();
+            bind_dirs.extend(dirs);
+        }
+
+        // mount() only applies MS_RDONLY to the top mount, so any mount points below the
+        // toolchain root need to be remounted individually.  the pseudo-filesystems are skipped
+        // as we mount our own /dev and /proc anyway
+        let mut readonly = vec![root_dir.clone()];
+        for shadow in &shadows {
+            readonly.push(shadow.dir.clone());
+            readonly.extend(shadow.entries.iter().filter_map(|entry| match entry {
+                ShadowEntry::Dir(_, target) | ShadowEntry::File(_, target) => Some(target.clone()),
+                ShadowEntry::Symlink(..) => None,
+            }));
+        }
+        for mount_point in self.submounts(&toolchain)? {
+            let skip = ["/proc", "/sys", "/dev"]
+                .iter()
+                .any(|dir| mount_point.starts_with(dir));
+            if !skip {
+                readonly.push(root_dir.join(relative(&mount_point, &toolchain)));
+            }
+        }
+
+        // devices missing on the host (e.g. in a minimal container) are left out
+        let dev_dir = self.mount_point(&root_dir, Path::new("/dev"));
+        let devices = Self::DEVICES
+            .iter()
+            .map(|name| (Path::new("/dev").join(name), dev_dir.join(name)))
+            .filter(|(source, _)| source.exists())
+            .collect();
+        let dev_links = Self::DEV_LINKS
+            .iter()
+            .map(|(name, link)| (c_path(Path::new(link)), c_path(&dev_dir.join(name))))
+            .collect();
+
+        let mut flags = CloneFlags::CLONE_NEWUSER | CloneFlags::CLONE_NEWNS
+            | CloneFlags::CLONE_NEWPID;
+        if !self.network {
+            flags |= CloneFlags::CLONE_NEWNET;
+        }
+
+        Ok(Setup {
+            namespaces: Namespaces::new(flags, true),
+            toolchain: toolchain.clone(),
+            devices,
+            dev_links,
+            pts_dir: dev_dir.join("pts"),
+            shm_dir: dev_dir.join("shm"),
+            dev_dir,
+            proc_dir: self.mount_point(&root_dir, Path::new("/proc")),
+            tmp_dir: self.mount_point(&root_dir, Path::new("/tmp")),
+            root_dir,
+            shadows,
+            missing,
+            readonly,
+            binds,
+            bind_dirs,
+            cur_dir: canonicalize(cur_dir)?,
+        })
+    }
+
+    // returns the path at which the given absolute path will be found while assembling the root
+    // filesystem
+    fn mount_point(&self, root_dir: &Path, path: &Path) -> PathBuf {
+        root_dir.join(relative(path, Path::new("/")))
+    }
+
+    // reads the directories of the toolchain root that need to be shadowed for the given paths
+    // to exist inside the sandbox, returning them along with the directories that then need to be
+    // created (in the order they must be created)
+    fn shadows(
+        &self,
+        toolchain: &Path,
+        root_dir: &Path,
+        paths: &[PathBuf],
+    ) -> Result<(Vec<Shadow>, Vec<PathBuf>), SandboxError> {
+        let (shadowed, missing) = missing_dirs(paths, |dir| toolchain.join(dir).exists());
+        let missing = missing.iter().map(|dir| root_dir.join(dir)).collect();
+
+        let mut shadows = vec![];
+        for rel in shadowed {
+            let source_dir = toolchain.join(&rel);
+            let target_dir = root_dir.join(&rel);
+            let read_err = |e| SandboxError::ReadDir(path_to_string(&source_dir), e);
+
+            let mut entries = vec![];
+            for entry in fs::read_dir(&source_dir).map_err(&read_err)? {
+                let entry = entry.map_err(&read_err)?;
+                let (source, target) = (entry.path(), target_dir.join(entry.file_name()));
+                let kind = entry.file_type().map_err(&read_err)?;
+                entries.push(if kind.is_symlink() {
+                    let link = fs::read_link(&source).map_err(&read_err)?;
+                    ShadowEntry::Symlink(c_path(&link), c_path(&target))
+                } else if kind.is_dir() {
+                    ShadowEntry::Dir(source, target)
+                } else {
+                    ShadowEntry::File(source, target)
+                });
+            }
+            shadows.push(Shadow {
+                dir: target_dir,
+                entries,
+            });
+        }
+
+        Ok((shadows, missing))
+    }
+
+    // returns every mount point below the given directory (in the order they were mounted)
+    fn submounts(&self, dir: &Path) -> Result<Vec<PathBuf>, SandboxError> {
+        let mountinfo_err = |e| SandboxError::MountInfo(Self::MOUNTINFO.to_string(), e);
+
+        let file = File::open(Self::MOUNTINFO).map_err(&mountinfo_err)?;
+        mounts_below(BufReader::new(file), dir).map_err(&mountinfo_err)
+    }
+}
+
+// makes the given command run in a new user namespace (in which the user running mkpkg is either
+// itself or root) and, unless network access is allowed, a network namespace with nothing but a
+// loopback interface.  otherwise the command has the same view of the system
+pub fn isolate(cmd: &mut Command, network: bool, map_root: bool) {
+    let mut flags = CloneFlags::CLONE_NEWUSER;
+    if !network {
+        flags |= CloneFlags::CLONE_NEWNET;
+    }
+    let namespaces = Namespaces::new(flags, map_root);
+    // as with Sandbox::apply(), enter() is written to be safe to run between fork() and exec()
+    unsafe {
+        cmd.pre_exec(move || namespaces.enter());
+    }
+}
+
+// whether commands given to isolate() can run at all, which they can't if we are not allowed to
+// create namespaces (as is often the case in containers)
+pub fn isolation_supported() -> io::Result<bool> {
+    let mut cmd = Command::new("/bin/sh");
+    cmd.arg("-c")
+        .arg(":")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    isolate(&mut cmd, false, false);
+    match cmd.status() {
+        Ok(_) => Ok(true),
+        Err(ref e) if unsupported(e) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+// whether an error from spawning a command given to isolate() means that we could not create
+// the namespaces at all.  unshare() fails with EPERM if unprivileged user namespaces have been
+// disabled (or we are already confined, e.g. by seccomp) and with EINVAL if the kernel lacks them
+fn unsupported(err: &io::Error) -> bool {
+    matches!(err.raw_os_error(), Some(libc::EPERM) | Some(libc::EINVAL))
+}
+
+// the namespaces to create (which include a user namespace so that unprivileged users can create
+// the others) and how the user running mkpkg is mapped inside of them.  root can create the others
+// by itself, and staying outside of a user namespace lets it give files to any user
+struct Namespaces {
+    flags: CloneFlags,
+    uid_map: Vec<u8>,
+    gid_map: Vec<u8>,
+}
+
+impl Namespaces {
+    fn new(mut flags: CloneFlags, map_root: bool) -> Self {
+        if unistd::geteuid().is_root() {
+            flags.remove(CloneFlags::CLONE_NEWUSER);
+        }
+
+        let (uid, gid) = (unistd::getuid(), unistd::getgid());
+        let (inner_uid, inner_gid) = if map_root {
+            (0.to_string(), 0.to_string())
+        } else {
+            (uid.to_string(), gid.to_string())
+        };
+
+        Self {
+            flags,
+            uid_map: format!("{} {} 1", inner_uid, uid).into_bytes(),
+            gid_map: format!("{} {} 1", inner_gid, gid).into_bytes(),
+        }
+    }
+
+    // NOTE: like Setup::enter(), this runs between fork() and exec(), so it must not allocate
+    fn enter(&self) -> io::Result<()> {
+        sched::unshare(self.flags).map_err(nix_to_io)?;
+
+        if self.flags.contains(CloneFlags::CLONE_NEWUSER) {
+            write_file(Path::new("/proc/self/setgroups"), b"deny")?;
+            write_file(Path::new("/proc/self/uid_map"), &self.uid_map)?;
+            write_file(Path::new("/proc/self/gid_map"), &self.gid_map)?;
+        }
+
+        // a new network namespace starts out with its loopback interface down
+        if self.flags.contains(CloneFlags::CLONE_NEWNET) {
+            loopback_up()?;
+        }
+        Ok(())
+    }
+}
+
+// a directory of the toolchain root that is replaced by a tmpfs inside the sandbox, with
+// everything it contains mounted (or, for symlinks, recreated) at the same place on top of it
+struct Shadow {
+    dir: PathBuf,
+    entries: Vec<ShadowEntry>,
+}
+
+enum ShadowEntry {
+    Dir(PathBuf, PathBuf),
+    File(PathBuf, PathBuf),
+    // the contents of the symlink and where to create it
+    Symlink(CString, CString),
+}
+
+// state needed by the child process to enter the sandbox
+struct Setup {
+    namespaces: Namespaces,
+    toolchain: PathBuf,
+    root_dir: PathBuf,
+    shadows: Vec<Shadow>,
+    // mount points that do not exist in the toolchain root (and are thus created in a shadow)
+    missing: Vec<PathBuf>,
+    readonly: Vec<PathBuf>,
+    binds: Vec<(PathBuf, PathBuf)>,
+    // every directory leading up to the targets of binds
+    bind_dirs: Vec<PathBuf>,
+    dev_dir: PathBuf,
+    // host devices and where they are bound to in the new /dev
+    devices: Vec<(PathBuf, PathBuf)>,
+    // the contents of each symlink in the new /dev and where to create it
+    dev_links: Vec<(CString, CString)>,
+    pts_dir: PathBuf,
+    shm_dir: PathBuf,
+    proc_dir: PathBuf,
+    tmp_dir: PathBuf,
+    cur_dir: PathBuf,
+}
+
+impl Setup {
+    // NOTE: this runs in the child process between fork() and exec(), so it must not allocate
+    fn enter(&self) -> io::Result<()> {
+        // root inside the namespaces is mapped to the user running mkpkg
+        self.namespaces.enter()?;
+
+        // only children of this process end up in the new PID namespace, so we let the child run
+        // the command while we wait for it to finish
+        match unistd::fork().map_err(nix_to_io)? {
+            ForkResult::Parent { child } => wait_and_exit(child),
+            ForkResult::Child => {}
+        }
+
+        self.mount_root()?;
+
+        unistd::chroot(&self.root_dir).map_err(nix_to_io)?;
+        unistd::chdir(&self.cur_dir).map_err(nix_to_io)
+    }
+
+    fn mount_root(&self) -> io::Result<()> {
+        const NONE: Option<&'static [u8]> = None;
+
+        // make sure none of our mounts propagate back to the host
+        mount::mount(NONE, "/", NONE, MsFlags::MS_REC | MsFlags::MS_PRIVATE, NONE)
+            .map_err(nix_to_io)?;
+
+        let root_shadowed = self.shadows
+            .first()
+            .is_some_and(|shadow| shadow.dir == self.root_dir);
+        if !root_shadowed {
+            mount::mount(
+                Some(&self.toolchain),
+                &self.root_dir,
+                NONE,
+                MsFlags::MS_BIND | MsFlags::MS_REC,
+                NONE,
+            ).map_err(nix_to_io)?;
+        }
+        for shadow in &self.shadows {
+            self.mount_shadow(shadow)?;
+        }
+        for dir in &self.missing {
+            match unistd::mkdir(dir, Mode::from_bits_truncate(0o755)) {
+                Ok(()) | Err(nix::Error::Sys(nix::errno::Errno::EEXIST)) => {}
+                Err(e) => return Err(nix_to_io(e)),
+            }
+        }
+
+        for dir in &self.readonly {
+            let flags = MsFlags::MS_BIND | MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY
+                | locked_flags(dir)?;
+            mount::mount(NONE, dir, NONE, flags, NONE).map_err(nix_to_io)?;
+        }
+
+        self.mount_dev()?;
+        mount::mount(
+            Some("proc"),
+            &self.proc_dir,
+            Some("proc"),
+            MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+            NONE,
+        ).map_err(nix_to_io)?;
+        mount::mount(
+            Some("tmpfs"),
+            &self.tmp_dir,
+            Some("tmpfs"),
+            MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+            NONE,
+        ).map_err(nix_to_io)?;
+
+        for dir in &self.bind_dirs {
+            // this will fail if the directory already exists (or can't be created because it is
+            // on a read-only filesystem, in which case it should already exist)
+            let _ = unistd::mkdir(dir, Mode::S_IRWXU);
+        }
+        for (source, target) in &self.binds {
+            mount::mount(
+                Some(source),
+                target,
+                NONE,
+                MsFlags::MS_BIND | MsFlags::MS_REC,
+                NONE,
+            ).map_err(nix_to_io)?;
+        }
+
+        Ok(())
+    }
+
+    // rather than exposing every device of the host, /dev is a tmpfs with only the few devices
+    // builds need, along with a private devpts instance and shared memory
+    fn mount_dev(&self) -> io::Result<()> {
+        const NONE: Option<&'static [u8]> = None;
+
+        mount::mount(
+            Some("tmpfs"),
+            &self.dev_dir,
+            Some("tmpfs"),
+            MsFlags::MS_NOSUID | MsFlags::MS_NOEXEC,
+            Some("mode=755"),
+        ).map_err(nix_to_io)?;
+
+        for (source, target) in &self.devices {
+            let flags = OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_CLOEXEC;
+            let fd = fcntl::open(target, flags, Mode::S_IRUSR).map_err(nix_to_io)?;
+            let _ = unistd::close(fd);
+            mount::mount(Some(source), target, NONE, MsFlags::MS_BIND, NONE)
+                .map_err(nix_to_io)?;
+        }
+
+        unistd::mkdir(&self.pts_dir, Mode::from_bits_truncate(0o755)).map_err(nix_to_io)?;
+        mount::mount(
+            Some("devpts"),
+            &self.pts_dir,
+            Some("devpts"),
+            MsFlags::MS_NOSUID | MsFlags::MS_NOEXEC,
+            Some("newinstance,ptmxmode=0666,mode=620"),
+        ).map_err(nix_to_io)?;
+
+        unistd::mkdir(&self.shm_dir, Mode::from_bits_truncate(0o1777)).map_err(nix_to_io)?;
+        mount::mount(
+            Some("tmpfs"),
+            &self.shm_dir,
+            Some("tmpfs"),
+            MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+            Some("mode=1777"),
+        ).map_err(nix_to_io)?;
+
+        for (link, target) in &self.dev_links {
+            if unsafe { libc::symlink(link.as_ptr(), target.as_ptr()) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn mount_shadow(&self, shadow: &Shadow) -> io::Result<()> {
+        const NONE: Option<&'static [u8]> = None;
+
+        mount::mount(
+            Some("tmpfs"),
+            &shadow.dir,
+            Some("tmpfs"),
+            MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+            Some("mode=755"),
+        ).map_err(nix_to_io)?;
+
+        for entry in &shadow.entries {
+            let (source, target) = match entry {
+                ShadowEntry::Dir(source, target) => {
+                    unistd::mkdir(target, Mode::S_IRWXU).map_err(nix_to_io)?;
+                    (source, target)
+                }
+                ShadowEntry::File(source, target) => {
+                    let flags = OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_CLOEXEC;
+                    let fd = fcntl::open(target, flags, Mode::S_IRUSR).map_err(nix_to_io)?;
+                    let _ = unistd::close(fd);
+                    (source, target)
+                }
+                ShadowEntry::Symlink(link, target) => {
+                    if unsafe { libc::symlink(link.as_ptr(), target.as_ptr()) } < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    continue;
+                }
+            };
+            mount::mount(
+                Some(source),
+                target,
+                NONE,
+                MsFlags::MS_BIND | MsFlags::MS_REC,
+                NONE,
+            ).map_err(nix_to_io)?;
+        }
+
+        Ok(())
+    }
+}
+
+// unprivileged users cannot clear certain flags when remounting, so we need to carry them over
+fn locked_flags(path: &Path) -> io::Result<MsFlags> {
+    let stat = statvfs::statvfs(path).map_err(nix_to_io)?;
+    let flags = stat.flags();
+
+    let mut result = MsFlags::empty();
+    for &(fs_flag, ms_flag) in &[
+        (FsFlags::ST_NOSUID, MsFlags::MS_NOSUID),
+        (FsFlags::ST_NODEV, MsFlags::MS_NODEV),
+        (FsFlags::ST_NOEXEC, MsFlags::MS_NOEXEC),
+        (FsFlags::ST_NOATIME, MsFlags::MS_NOATIME),
+        (FsFlags::ST_NODIRATIME, MsFlags::MS_NODIRATIME),
+        (FsFlags::ST_RELATIME, MsFlags::MS_RELATIME),
+    ] {
+        if flags.contains(fs_flag) {
+            result |= ms_flag;
+        }
+    }
+    Ok(result)
+}
+
+// struct ifreq from <net/if.h> (only the part of the union we need)
+#[repr(C)]
+struct IfReq {
+    name: [libc::c_char; libc::IFNAMSIZ],
+    flags: libc::c_short,
+    _pad: [u8; 22],
+}
+
+fn loopback_up() -> io::Result<()> {
+    let sock = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if sock < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut req = IfReq {
+        name: [0; libc::IFNAMSIZ],
+        flags: 0,
+        _pad: [0; 22],
+    };
+    for (dest, &byte) in req.name.iter_mut().zip(b"lo") {
+        *dest = byte as libc::c_char;
+    }
+
+    let res = unsafe {
+        if libc::ioctl(sock, libc::SIOCGIFFLAGS, &mut req) < 0 {
+            -1
+        } else {
+            req.flags |= libc::IFF_UP as libc::c_short;
+            libc::ioctl(sock, libc::SIOCSIFFLAGS, &req)
+        }
+    };
+    let err = io::Error::last_os_error();
+    let _ = unistd::close(sock);
+
+    if res < 0 {
+        Err(err)
+    } else {
+        Ok(())
+    }
+}
+
+fn write_file(path: &Path, data: &[u8]) -> io::Result<()> {
+    let fd = fcntl::open(path, OFlag::O_WRONLY, Mode::empty()).map_err(nix_to_io)?;
+    let res = unistd::write(fd, data).map_err(nix_to_io);
+    let _ = unistd::close(fd);
+    res.map(|_| ())
+}
+
+// waits for the process running the command and then exits with the same status
+fn wait_and_exit(child: Pid) -> ! {
+    // we must not hold onto any of the file descriptors from our parent (e.g. the write end of
+    // the command's stdin), as otherwise the command would never see EOF
+    for fd in 3..1024 {
+        unsafe {
+            libc::close(fd);
+        }
+    }
+
+    let code = loop {
+        match wait::waitpid(child, None) {
+            Ok(WaitStatus::Exited(_, code)) => break code,
+            Ok(WaitStatus::Signaled(_, signal, _)) => break 128 + signal as i32,
+            Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => continue,
+            Ok(_) => continue,
+            Err(_) => break 1,
+        }
+    };
+    unsafe { libc::_exit(code) }
+}
+
+// reads the mount points below the given directory from mountinfo (see proc(5)), in which the
+// fifth field of each line is the mount point
+fn mounts_below<R: BufRead>(mountinfo: R, dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut mounts = vec![];
+    for line in mountinfo.lines() {
+        if let Some(mount_point) = line?.split(' ').nth(4) {
+            let mount_point = PathBuf::from(unescape_mountinfo(mount_point));
+            if mount_point != dir && mount_point.starts_with(dir) {
+                mounts.push(mount_point);
+            }
+        }
+    }
+    Ok(mounts)
+}
+
+// finds the directories (relative to the root) that need to be shadowed for the given absolute
+// paths to exist, given which directories already do (ordered so that parents come before their
+// children), along with the directories that then need to be created (in the order they must be
+// created)
+fn missing_dirs<F: Fn(&Path) -> bool>(
+    paths: &[PathBuf],
+    exists: F,
+) -> (Vec<PathBuf>, Vec<PathBuf>) {
+    let mut shadowed: Vec<PathBuf> = vec![];
+    let mut missing = vec![];
+    for path in paths {
+        let rel = relative(path, Path::new("/"));
+        let existing = match rel.ancestors().find(|dir| exists(dir)) {
+            Some(dir) if dir == rel => continue,
+            Some(dir) => dir.to_path_buf(),
+            None => PathBuf::new(),
+        };
+
+        let mut dirs: Vec<PathBuf> = rel.ancestors()
+            .take_while(|dir| *dir != existing)
+            .map(PathBuf::from)
+            .collect();
+        dirs.reverse();
+        for dir in dirs {
+            if !missing.contains(&dir) {
+                missing.push(dir);
+            }
+        }
+        if !shadowed.contains(&existing) {
+            shadowed.push(existing);
+        }
+    }
+    shadowed.sort_by_key(|dir| dir.components().count());
+
+    (shadowed, missing)
+}
+
+// NOTE: paths cannot contain NUL bytes, so this never panics
+fn c_path(path: &Path) -> CString {
+    CString::new(path.as_os_str().as_bytes()).unwrap()
+}
+
+fn canonicalize(path: &Path) -> Result<PathBuf, SandboxError> {
+    path.canonicalize()
+        .map_err(|e| SandboxError::Canonicalize(path_to_string(path), e))
+}
+
+// strips the prefix from the path (both of which must be absolute), leaving only normal
+// components so the result can safely be joined onto another directory
+fn relative(path: &Path, prefix: &Path) -> PathBuf {
+    path.strip_prefix(prefix)
+        .unwrap_or(path)
+        .components()
+        .filter(|comp| matches!(comp, Component::Normal(_)))
+        .collect()
+}
+
+// mount points in mountinfo have spaces, tabs, newlines, and backslashes escaped as octal
+fn unescape_mountinfo(field: &str) -> String {
+    let mut result = String::new();
+    let mut rest = field;
+    while let Some(pos) = rest.find('\\') {
+        result.push_str(&rest[..pos]);
+        let escape = rest.get(pos + 1..pos + 4);
+        match escape.and_then(|code| u8::from_str_radix(code, 8).ok()) {
+            Some(byte) => {
+                result.push(byte as char);
+                rest = &rest[pos + 4..];
+            }
+            None => {
+                result.push('\\');
+                rest = &rest[pos + 1..];
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unescape_mountinfo_octal() {
+        assert_eq!(unescape_mountinfo("/mnt/my\\040disk"), "/mnt/my disk");
+        assert_eq!(unescape_mountinfo("/mnt/back\\134slash"), "/mnt/back\\slash");
+        assert_eq!(unescape_mountinfo("\\011tab\\012"), "\ttab\n");
+        assert_eq!(unescape_mountinfo("/mnt/plain"), "/mnt/plain");
+    }
+
+    #[test]
+    fn unescape_mountinfo_invalid() {
+        // anything but three octal digits is left alone
+        assert_eq!(unescape_mountinfo("/mnt/a\\09b"), "/mnt/a\\09b");
+        assert_eq!(unescape_mountinfo("/mnt/end\\04"), "/mnt/end\\04");
+        assert_eq!(unescape_mountinfo("/mnt/end\\"), "/mnt/end\\");
+    }
+
+    #[test]
+    fn relative_paths() {
+        let toolchain = Path::new("/opt/toolchain");
+        assert_eq!(relative(Path::new("/opt/toolchain/usr/lib"), toolchain), Path::new("usr/lib"));
+        assert_eq!(relative(toolchain, toolchain), Path::new(""));
+        assert_eq!(relative(Path::new("/tmp/build"), Path::new("/")), Path::new("tmp/build"));
+        // paths outside the prefix must still not escape the directory they are joined onto
+        assert_eq!(relative(Path::new("/tmp/../build"), toolchain), Path::new("tmp/build"));
+    }
+
+    #[test]
+    fn mounts_below_toolchain() {
+        let mountinfo = "\
+22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw
+23 22 0:5 / /proc rw,nosuid shared:2 - proc proc rw
+30 22 8:2 / /opt/toolchain rw,relatime shared:3 - ext4 /dev/sda2 rw
+31 30 8:3 / /opt/toolchain/usr rw,relatime shared:4 - ext4 /dev/sda3 rw
+32 30 8:4 / /opt/toolchain/my\\040mount rw,relatime shared:5 - ext4 /dev/sda4 rw
+33 22 8:5 / /opt/toolchain2 rw,relatime shared:6 - ext4 /dev/sda5 rw
+";
+        let mounts = mounts_below(mountinfo.as_bytes(), Path::new("/opt/toolchain")).unwrap();
+        assert_eq!(
+            mounts,
+            vec![
+                PathBuf::from("/opt/toolchain/usr"),
+                PathBuf::from("/opt/toolchain/my mount"),
+            ]
+        );
+    }
+
+    fn missing(paths: &[&str], existing: &[&str]) -> (Vec<PathBuf>, Vec<PathBuf>) {
+        let paths: Vec<PathBuf> = paths.iter().map(PathBuf::from).collect();
+        missing_dirs(&paths, |dir| existing.iter().any(|existing| dir == Path::new(existing)))
+    }
+
+    fn paths(paths: &[&str]) -> Vec<PathBuf> {
+        paths.iter().map(PathBuf::from).collect()
+    }
+
+    #[test]
+    fn missing_dirs_none() {
+        let (shadowed, missing) = missing(&["/dev", "/usr/src"], &["", "dev", "usr", "usr/src"]);
+        assert!(shadowed.is_empty());
+        assert!(missing.is_empty());
+    }
+
+    #[test]
+    fn missing_dirs_nested_writable() {
+        // the build directory is below a directory that the toolchain root only partly has
+        let (shadowed, missing) = missing(
+            &["/home/user/build/foo", "/home/user/build", "/tmp"],
+            &["", "home"],
+        );
+        assert_eq!(shadowed, paths(&["", "home"]));
+        assert_eq!(
+            missing,
+            paths(&["home/user", "home/user/build", "home/user/build/foo", "tmp"])
+        );
+    }
+
+    #[test]
+    fn missing_dirs_below_mount() {
+        // usr/src stands for a read-only mount within the toolchain root, which gets shadowed
+        // rather than anything above it
+        let (shadowed, missing) = missing(&["/usr/src/build", "/srv"], &["", "usr", "usr/src"]);
+        assert_eq!(shadowed, paths(&["", "usr/src"]));
+        assert_eq!(missing, paths(&["usr/src/build", "srv"]));
+    }
+}
@@ -0,0 +1,157 @@
+use std::fs::{self, DirBuilder};
+use std::io;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+use util::path_to_string;
+
+#[derive(Debug, Fail)]
+pub enum SignatureError {
+    #[fail(display = "could not read keyring directory '{}': {}", _0, _1)]
+    KeyringDir(String, #[cause] io::Error),
+
+    #[fail(display = "could not create directory '{}': {}", _0, _1)]
+    CreateDir(String, #[cause] io::Error),
+
+    #[fail(display = "could not remove directory '{}': {}", _0, _1)]
+    RemoveDir(String, #[cause] io::Error),
+
+    #[fail(display = "could not execute gpg: {}", _0)]
+    Spawn(#[cause] io::Error),
+
+    #[fail(display = "could not import the keys in '{}': {}", _0, _1)]
+    Import(String, String),
+
+    #[fail(display = "invalid signature '{}': {}", _0, _1)]
+    BadSignature(String, String),
+}
+
+// extensions of detached signatures, which are expected to be named after the file they sign
+pub const EXTENSIONS: &[&str] = &[".sig", ".asc"];
+
+// a private gpg home directory holding only the keys from the keyring directory, so that neither
+// the user's own keys nor their trust settings have any say in which signatures are accepted
+pub struct Keyring {
+    homedir: PathBuf,
+}
+
+impl Keyring {
+    pub fn create(homedir: &Path, keyring_dir: &Path) -> Result<Self, SignatureError> {
+        if homedir.exists() {
+            fs::remove_dir_all(homedir)
+                .map_err(|e| SignatureError::RemoveDir(path_to_string(homedir), e))?;
+        }
+        // gpg complains about home directories that other users can read
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(homedir)
+            .map_err(|e| SignatureError::CreateDir(path_to_string(homedir), e))?;
+
+        let mut keys = vec![];
+        let entries = fs::read_dir(keyring_dir)
+            .map_err(|e| SignatureError::KeyringDir(path_to_string(keyring_dir), e))?;
+        for entry in entries {
+            let entry = entry
+                .map_err(|e| SignatureError::KeyringDir(path_to_string(keyring_dir), e))?;
+            if entry.file_type().map(|kind| kind.is_file()).unwrap_or(false) {
+                keys.push(entry.path());
+            }
+        }
+        keys.sort();
+
+        let keyring = Self {
+            homedir: homedir.to_path_buf(),
+        };
+        // without any keys every signature will simply fail to verify
+        if !keys.is_empty() {
+            let output = keyring
+                .gpg()
+                .arg("--import")
+                .args(&keys)
+                .output()
+                .map_err(SignatureError::Spawn)?;
+            if !output.status.success() {
+                Err(SignatureError::Import(path_to_string(keyring_dir), gpg_error(&output)))?;
+            }
+        }
+
+        Ok(keyring)
+    }
+
+    // checks that the detached signature is a good signature of the given file made by one of the
+    // given keys (each a full fingerprint of either the signing key or its primary key)
+    pub fn verify(
+        &self,
+        sig_path: &Path,
+        data_path: &Path,
+        valid_keys: &[String],
+    ) -> Result<(), SignatureError> {
+        let output = self.gpg()
+            .args(["--status-fd", "1", "--verify"])
+            .arg(sig_path)
+            .arg(data_path)
+            .output()
+            .map_err(SignatureError::Spawn)?;
+        let bad_sig =
+            |reason: String| SignatureError::BadSignature(path_to_string(sig_path), reason);
+
+        // see doc/DETAILS in the gnupg source for the format of the status lines
+        let mut good = false;
+        let mut signers = vec![];
+        let status = String::from_utf8_lossy(&output.stdout);
+        for line in status.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 2 || fields[0] != "[GNUPG:]" {
+                continue;
+            }
+            match fields[1] {
+                "GOODSIG" => good = true,
+                "VALIDSIG" => {
+                    // the signing key comes first and its primary key (if known) last
+                    signers.extend(fields.get(2).cloned());
+                    signers.extend(fields.get(11).cloned());
+                }
+                // signatures from expired or revoked keys are no better than bad ones
+                "BADSIG" | "ERRSIG" | "EXPSIG" | "EXPKEYSIG" | "REVKEYSIG" => {
+                    Err(bad_sig(gpg_error(&output)))?;
+                }
+                _ => {}
+            }
+        }
+
+        if !output.status.success() || !good {
+            Err(bad_sig(gpg_error(&output)))?;
+        }
+        let trusted = signers
+            .iter()
+            .any(|signer| valid_keys.iter().any(|key| key.eq_ignore_ascii_case(signer)));
+        if !trusted {
+            Err(bad_sig(format!(
+                "made by {}, which is not in validpgpkeys",
+                signers.first().unwrap_or(&"an unknown key")
+            )))?;
+        }
+
+        Ok(())
+    }
+
+    fn gpg(&self) -> Command {
+        let mut gpg = Command::new("gpg");
+        gpg.arg("--homedir")
+            .arg(&self.homedir)
+            .args(["--batch", "--no-tty", "--no-autostart"]);
+        gpg
+    }
+}
+
+// gpg explains what went wrong on the last line it prints
+fn gpg_error(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr)
+        .lines()
+        .rfind(|line| !line.trim().is_empty())
+        .unwrap_or("gpg did not say why")
+        .trim()
+        .to_string()
+}
@@ -1,4 +1,5 @@
 use ansi_term::Color::{Green, Red, Yellow};
+use nix;
 use num_cpus;
 use walkdir::Error as WalkError;
 use walkdir::WalkDir;
@@ -7,7 +8,7 @@ use std::fmt;
 use std::fs;
 use std::io::{self, Write};
 use std::os::unix::fs::symlink;
-use std::path::{Path, StripPrefixError};
+use std::path::{Component, Path, StripPrefixError};
 
 #[derive(Debug, Fail)]
 pub enum UtilError {
@@ -26,6 +27,9 @@ pub enum UtilError {
     #[fail(display = "found invalid directory entry: {}", _0)]
     DirEntry(#[cause] WalkError),
 
+    #[fail(display = "refusing to copy symlink '{}' as it points to '{}', which is outside of the destination", _0, _1)]
+    UnsafeSymlink(String, String),
+
     #[fail(display = "found invalid path '{}': {}", _0, _1)]
     PathPrefix(String, #[cause] StripPrefixError),
 }
@@ -66,6 +70,25 @@ pub fn path_to_string<P: AsRef<Path> + ?Sized>(path: &P) -> String {
     format!("{}", path.as_ref().display())
 }
 
+// returns the path of an entry found by walking root relative to root.  walkdir only ever yields
+// paths within the directory it was given, so this can't fail for them
+pub fn relative_to<'a>(root: &Path, path: &'a Path) -> &'a Path {
+    path.strip_prefix(root).unwrap()
+}
+
+// nix reports errors from system calls (and paths it could not convert) using its own type
+pub fn nix_to_io(err: nix::Error) -> io::Error {
+    match err {
+        nix::Error::Sys(errno) => io::Error::from_raw_os_error(errno as i32),
+        _ => io::Error::other("invalid path"),
+    }
+}
+
+// quotes the given string so that sh treats it as a single word
+pub fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
 pub fn cpu_count() -> usize {
     num_cpus::get()
 }
@@ -77,21 +100,40 @@ where
 {
     let (source, dest) = (source.as_ref(), dest.as_ref());
 
-    let parent = match source.parent() {
+    let name = match source.file_name() {
         Some(val) => val,
         // FIXME: figure out what this should do (basically this means the source is '/', which i don't think can happen)
         None => unimplemented!(),
     };
 
+    copy_dir_as(source, &dest.join(name))
+}
+
+// like copy_dir(), but the copy is placed at dest itself rather than inside of it
+pub fn copy_dir_as<S, D>(source: &S, dest: &D) -> Result<(), UtilError>
+where
+    S: AsRef<Path> + ?Sized,
+    D: AsRef<Path> + ?Sized,
+{
+    let (source, dest) = (source.as_ref(), dest.as_ref());
+
+    // symlinks may refer to anything alongside the copy, but nothing further up
+    let name = dest.file_name().map(Path::new).unwrap_or_else(|| Path::new(""));
+
     for entry in WalkDir::new(source) {
         let entry = entry.map_err(|e| UtilError::DirEntry(e))?;
 
         let subpath = entry
             .path()
-            .strip_prefix(parent)
+            .strip_prefix(source)
             .map_err(|e| UtilError::PathPrefix(path_to_string(entry.path()), e))?;
 
-        let path = dest.join(subpath);
+        // joining an empty path would add a trailing slash, which breaks copying a single file
+        let path = if subpath.as_os_str().is_empty() {
+            dest.to_path_buf()
+        } else {
+            dest.join(subpath)
+        };
         let file_type = entry.file_type();
         if file_type.is_dir() {
             fs::create_dir(&path).map_err(|e| UtilError::CreateDir(path_to_string(&path), e))?;
@@ -103,6 +145,12 @@ where
             // let's just assume it's a symlink
             let target = fs::read_link(entry.path())
                 .map_err(|e| UtilError::ReadLink(path_to_string(entry.path()), e))?;
+            if !link_is_contained(&name.join(subpath), &target) {
+                Err(UtilError::UnsafeSymlink(
+                    path_to_string(entry.path()),
+                    path_to_string(&target),
+                ))?
+            }
             symlink(&target, &path).map_err(|e| {
                 UtilError::CreateSymlink(path_to_string(&path), path_to_string(&target), e)
             })?;
@@ -111,3 +159,67 @@ where
 
     Ok(())
 }
+
+// checks whether a symlink at the given path (relative to some directory) refers to something
+// within that directory.  the target may only use ".." at the start, as afterwards it could be
+// going up from a directory that is itself a symlink
+pub fn link_is_contained(path: &Path, target: &Path) -> bool {
+    let mut depth = path.components()
+        .filter(|comp| matches!(comp, Component::Normal(_)))
+        .count()
+        .saturating_sub(1);
+    let mut descended = false;
+    for component in target.components() {
+        match component {
+            Component::ParentDir if !descended && depth > 0 => depth -= 1,
+            Component::Normal(_) => descended = true,
+            Component::CurDir => {}
+            _ => return false,
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contained(path: &str, target: &str) -> bool {
+        link_is_contained(Path::new(path), Path::new(target))
+    }
+
+    #[test]
+    fn targets_below_the_link() {
+        assert!(contained("link", "file"));
+        assert!(contained("link", "./dir/file"));
+        assert!(contained("dir/link", "sub/file"));
+    }
+
+    #[test]
+    fn targets_up_to_the_directory() {
+        assert!(contained("dir/link", "../file"));
+        assert!(contained("a/b/c/link", "../../file"));
+        assert!(contained("a/b/c/link", "../../.."));
+        assert!(contained("./a/./link", "../file"));
+    }
+
+    #[test]
+    fn targets_outside_the_directory() {
+        assert!(!contained("link", ".."));
+        assert!(!contained("link", "../file"));
+        assert!(!contained("dir/link", "../../file"));
+    }
+
+    #[test]
+    fn absolute_targets() {
+        assert!(!contained("link", "/"));
+        assert!(!contained("dir/link", "/etc/passwd"));
+    }
+
+    #[test]
+    fn parent_after_descending() {
+        // dir may itself be a symlink, so dir/.. need not be where the link is
+        assert!(!contained("link", "dir/../file"));
+        assert!(!contained("a/b/link", "../dir/../file"));
+    }
+}